@@ -0,0 +1,236 @@
+//! The `check_config` command, which validates the config file more strictly than [`Config::load`].
+
+use {
+    std::{
+        collections::HashSet,
+        env,
+    },
+    chrono::prelude::*,
+    itertools::Itertools as _,
+    serde_json::Value as Json,
+    bitbar_wurstmineberg_status::{
+        Client,
+        Uid,
+    },
+    crate::{
+        Error,
+        files::{
+            Config,
+            NotifyConfig,
+            ServerConfig,
+            VersionMatchDetails,
+        },
+        launcher,
+        util::struct_fields,
+    },
+};
+
+/// The Levenshtein distance between two strings.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect_vec();
+    let mut row = (0..=b.len()).collect_vec();
+    for (i, char_a) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, &char_b) in b.iter().enumerate() {
+            let above = row[j + 1];
+            row[j + 1] = (diagonal + usize::from(char_a != char_b)).min(row[j] + 1).min(above + 1);
+            diagonal = above;
+        }
+    }
+    row[b.len()]
+}
+
+/// The known key most likely meant by a misspelled key, if any is similar enough.
+fn suggestion<'a>(key: &str, known: &[&'a str]) -> Option<&'a str> {
+    let normalize = |key: &str| key.chars().filter(|&c| c != '_' && c != '-').flat_map(char::to_lowercase).collect::<String>();
+    // catches snake_case and kebab-case spellings of camelCase keys
+    if let Some(&known_key) = known.iter().find(|&&known_key| normalize(known_key) == normalize(key)) { return Some(known_key) }
+    known.iter()
+        .map(|&known_key| (edit_distance(key, known_key), known_key))
+        .filter(|&(distance, _)| distance <= 2.max(key.len() / 3))
+        .min()
+        .map(|(_, known_key)| known_key)
+}
+
+/// Describes each unknown key in the given config value and its known subsections, such as misspelled entries, which are otherwise ignored.
+pub(crate) fn unknown_keys(value: &Json) -> Vec<String> {
+    fn check(messages: &mut Vec<String>, value: &Json, known: &[&'static str], path: &str) {
+        let Json::Object(map) = value else { return };
+        for key in map.keys() {
+            // allows referencing the output of the print_config_schema command
+            if path.is_empty() && key == "$schema" { continue }
+            if !known.contains(&&**key) {
+                if let Some(suggestion) = suggestion(key, known) {
+                    messages.push(format!("unknown key {path}{key}, did you mean {path}{suggestion}?"));
+                } else {
+                    messages.push(format!("unknown key {path}{key}"));
+                }
+            }
+        }
+    }
+
+    let mut messages = Vec::default();
+    check(&mut messages, value, struct_fields::<Config>(), "");
+    if let Some(notify) = value.get("notify") {
+        check(&mut messages, notify, struct_fields::<NotifyConfig>(), "notify.");
+    }
+    if let Some(Json::Array(servers)) = value.get("servers") {
+        for (idx, server) in servers.iter().enumerate() {
+            check(&mut messages, server, struct_fields::<ServerConfig>(), &format!("servers[{idx}]."));
+        }
+    }
+    if let Some(Json::Object(version_match)) = value.get("versionMatch") {
+        for (profile_id, entry) in version_match {
+            check(&mut messages, entry, struct_fields::<VersionMatchDetails>(), &format!("versionMatch.{profile_id}."));
+        }
+    }
+    messages
+}
+
+struct Report {
+    problems: usize,
+}
+
+impl Report {
+    fn problem(&mut self, message: impl AsRef<str>) {
+        println!("error: {}", message.as_ref());
+        self.problems += 1;
+    }
+
+    fn warning(&self, message: impl AsRef<str>) {
+        println!("warning: {}", message.as_ref());
+    }
+
+    fn players(&mut self, entry: &str, players: &[Uid], known: &HashSet<&Uid>) {
+        // players who aren't Wurstmineberg people are identified by Minecraft username, which can't be checked
+        for uid in players.iter().filter(|uid| uid.is_person()) {
+            if !known.contains(&uid) {
+                self.problem(format!("{entry} contains {uid}, who is not in the people list"));
+            }
+        }
+    }
+}
+
+/// Validates the config file and prints a report. Returns the number of problems found.
+pub(crate) async fn check_config(http_client: &reqwest::Client) -> Result<usize, Error> {
+    let mut report = Report { problems: 0 };
+    let path = match Config::path() {
+        Ok(path) => path,
+        Err(e) => {
+            report.problem(e.to_string());
+            return Ok(report.problems)
+        }
+    };
+    if let Some(path) = path {
+        println!("checking {}", path.display());
+        let value = match Config::read_file(&path) {
+            Ok(value) => value,
+            Err(e) => {
+                report.problem(e.to_string());
+                return Ok(report.problems)
+            }
+        };
+        for message in unknown_keys(&value) {
+            report.problem(message);
+        }
+    } else {
+        println!("no config file found, checking the default config");
+    }
+    let env_vars = struct_fields::<Config>().iter().map(|key| Config::env_var_name(key)).collect_vec();
+    let env_var_refs = env_vars.iter().map(|var| &**var).collect_vec();
+    for (var, _) in env::vars_os() {
+        let Some(var) = var.to_str() else { continue };
+        if var.starts_with("WURSTMINEBERG_") && !env_var_refs.contains(&var) {
+            if let Some(suggestion) = suggestion(var, &env_var_refs) {
+                report.problem(format!("unknown environment variable {var}, did you mean {suggestion}?"));
+            } else {
+                report.problem(format!("unknown environment variable {var}"));
+            }
+        }
+    }
+    let config = match Config::load_with_sources() {
+        Ok((config, sources)) => {
+            for (key, source) in sources {
+                println!("{key}: from {source}");
+            }
+            config
+        }
+        Err(e) => {
+            report.problem(e.to_string());
+            return Ok(report.problems)
+        }
+    };
+    let mut all_people = HashSet::<Uid>::default();
+    let mut people_complete = true;
+    for (idx, server_config) in config.servers().iter().enumerate() {
+        let Some(ref api_base_url) = server_config.api_base_url else {
+            people_complete = false;
+            continue
+        };
+        match Client::new(http_client.clone(), api_base_url.clone()).people().await {
+            Ok(people) => {
+                let server_people = people.people.into_keys().collect::<HashSet<_>>();
+                report.players(&format!("servers[{idx}].ignoredPlayers"), &server_config.ignored_players, &server_people.iter().collect());
+                all_people.extend(server_people);
+            }
+            Err(e) => {
+                report.warning(format!("could not check player IDs for server {}: {e}", server_config.name));
+                people_complete = false;
+            }
+        }
+    }
+    // players missing from the known people lists might be on a server without a people list
+    if people_complete {
+        let all_people = all_people.iter().collect();
+        report.players("ignoredPlayers", &config.ignored_players, &all_people);
+        report.players("undeferPlayers", &config.undefer_players, &all_people);
+        report.players("watchedPlayers", &config.watched_players, &all_people);
+        if let Some(players) = config.notify.as_ref().and_then(|notify| notify.players.as_deref()) {
+            report.players("notify.players", players, &all_people);
+        }
+    }
+    for (profile_id, result) in launcher::check(&config) {
+        if let Err(e) = result {
+            report.problem(format!("versionMatch.{profile_id}: {e}"));
+        }
+    }
+    for spec in &config.defer_specs {
+        let next: Result<Option<DateTime<Utc>>, _> = timespec::next(spec.clone());
+        match next {
+            Ok(Some(_)) => {}
+            Ok(None) => report.problem(format!("deferSpecs entry {:?} matches no dates", spec.iter().format(" ").to_string())),
+            Err(e) => report.problem(format!("deferSpecs entry {:?} is invalid: {e}", spec.iter().format(" ").to_string())),
+        }
+    }
+    match report.problems {
+        0 => println!("no problems found"),
+        1 => println!("1 problem found"),
+        n => println!("{n} problems found"),
+    }
+    Ok(report.problems)
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        serde_json::json,
+        super::*,
+    };
+
+    #[test]
+    fn unknown_config_keys() {
+        let value = json!({
+            "$schema": "wurstmineberg.schema.json",
+            "show_if_empty": true,
+            "zoom": 2,
+            "notify": {"bogus": 1},
+            "servers": [{"name": "Example", "mainWorl": "world"}],
+        });
+        assert_eq!(unknown_keys(&value), [
+            "unknown key show_if_empty, did you mean showIfEmpty?",
+            "unknown key notify.bogus",
+            "unknown key servers[0].mainWorl, did you mean servers[0].mainWorld?",
+        ]);
+    }
+}
@@ -1,48 +1,106 @@
 use {
     std::{
+        borrow::Cow,
         collections::{
             BTreeMap,
-            btree_map,
+            HashMap,
+            HashSet,
         },
+        env,
         fmt,
-        fs::File,
-        io::Cursor,
-        path::PathBuf,
+        fs::{
+            self,
+            File,
+        },
+        io::BufReader,
+        path::{
+            Path,
+            PathBuf,
+        },
+        time::SystemTime,
+    },
+    chrono::{
+        TimeDelta,
+        prelude::*,
+    },
+    futures::stream::{
+        self,
+        StreamExt as _,
+    },
+    image::imageops::FilterType,
+    itertools::Itertools as _,
+    reqwest::{
+        StatusCode,
+        header::{
+            ETAG,
+            HeaderName,
+            IF_MODIFIED_SINCE,
+            IF_NONE_MATCH,
+            LAST_MODIFIED,
+        },
     },
-    bitbar::attr::Image,
-    chrono::prelude::*,
-    directories::UserDirs,
-    image::{
-        ImageFormat,
-        imageops::FilterType,
+    schemars::{
+        JsonSchema,
+        Schema,
+        SchemaGenerator,
+        json_schema,
     },
-    num_traits::One,
     serde::{
         Deserialize,
         Deserializer,
         Serialize,
-        de::Visitor,
+        de::{
+            DeserializeOwned,
+            Visitor,
+        },
+    },
+    serde_json::{
+        Value as Json,
+        json,
     },
-    serde_json::Value as Json,
+    url::Url,
     xdg::BaseDirectories,
-    crate::{
+    bitbar_wurstmineberg_status::{
         AvatarInfo,
-        Error,
+        Client,
+        DEFAULT_API_BASE_URL,
         Uid,
-        util::ResponseExt as _,
+    },
+    crate::{
+        DEFAULT_SERVER_NAME,
+        Error,
+        MAIN_WORLD,
+        check,
+        output::Output,
+        util::{
+            ResponseExt as _,
+            path_component,
+            struct_fields,
+            write_atomic,
+        },
     },
 };
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub(crate) enum VersionLink {
+    #[default]
     Enabled,
     Alternate,
     Disabled,
 }
 
-impl Default for VersionLink {
-    fn default() -> VersionLink {
-        VersionLink::Enabled
+impl JsonSchema for VersionLink {
+    fn schema_name() -> Cow<'static, str> {
+        Cow::Borrowed("VersionLink")
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        json_schema!({
+            "oneOf": [
+                { "type": "boolean" },
+                { "const": "alt" },
+            ],
+        })
     }
 }
 
@@ -74,59 +132,358 @@ impl<'de> Visitor<'de> for VersionLinkVisitor {
     }
 }
 
-#[derive(Debug, Deserialize)]
+/// The plugin configuration, loaded from `bitbar/plugins/wurstmineberg.json` or `bitbar/plugins/wurstmineberg.toml` in an XDG config directory,
+/// with top-level entries overridden by `WURSTMINEBERG_*` environment variables. See the readme for details.
+#[derive(Debug, Deserialize, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub(crate) struct Config {
+    /// The base URL of the Wurstmineberg API.
+    #[serde(default = "default_api_base_url")]
+    pub(crate) api_base_url: Url,
+    /// The maximum number of player avatars downloaded at the same time.
+    #[serde(default = "default_avatar_concurrency")]
+    pub(crate) avatar_concurrency: usize,
+    /// The number of seconds after which cached player avatars are checked for changes.
+    #[serde(default = "default_avatar_max_age")]
+    pub(crate) avatar_max_age: u32,
+    /// Show the number of `watchedPlayers` online in the menu bar, e.g. `2★/5`.
+    #[serde(default)]
+    pub(crate) count_watched_players: bool,
+    /// Timespecs for the “Defer Until” menu items, each as an array of words, e.g. `["tomorrow"]` or `["in", "1", "hour"]`.
     #[serde(default)]
     pub(crate) defer_specs: Vec<Vec<String>>,
+    /// Players who should not be listed.
     #[serde(default)]
     pub(crate) ignored_players: Vec<Uid>,
+    /// The path to the official Minecraft launcher's `launcher_profiles.json`, if not in the default location.
+    #[serde(default)]
+    pub(crate) launcher_profiles_path: Option<PathBuf>,
+    /// If present, a desktop notification is shown when a player joins or leaves a world.
+    #[serde(default)]
+    pub(crate) notify: Option<NotifyConfig>,
+    /// The output format when the plugin is run without a subcommand.
+    #[serde(default)]
+    pub(crate) output: Output,
+    /// Addresses of Minecraft servers to query using Server List Ping instead of the API, keyed by world name.
+    #[serde(default)]
+    pub(crate) ping_addresses: BTreeMap<String, String>,
+    /// How many hours players are listed in the “Recently Online” submenu after leaving. `0` disables the submenu.
+    #[serde(default = "default_recently_online_hours")]
+    pub(crate) recently_online_hours: u16,
+    /// Servers to monitor instead of Wurstmineberg.
+    #[serde(default)]
+    pub(crate) servers: Vec<ServerConfig>,
+    /// Keep showing a deferred plugin, with a menu showing when the deferral ends.
+    #[serde(default)]
+    pub(crate) show_deferred: bool,
+    /// Show the plugin even if the main world is running but no players are online.
     #[serde(default)]
     pub(crate) show_if_empty: bool,
+    /// Show the plugin even if the main world is not running and no players are online.
     #[serde(default)]
     pub(crate) show_if_offline: bool,
+    /// Show the plugin even if none of the `watchedPlayers` are online. Has no effect if `watchedPlayers` is empty.
+    #[serde(default = "make_true")]
+    pub(crate) show_if_unwatched: bool,
+    /// Color the menu bar icon with the favorite color of the only player online.
     #[serde(default = "make_true")]
     pub(crate) single_color: bool,
+    /// The root of the website that `apiBaseUrl` belongs to, used for links to people pages. Derived from `apiBaseUrl` if omitted.
+    #[serde(default)]
+    pub(crate) site_url: Option<Url>,
+    /// Players whose coming online cancels an active deferral.
+    #[serde(default)]
+    pub(crate) undefer_players: Vec<Uid>,
+    /// Whether the version item links to the Minecraft Wiki: `true`, `false`, or `"alt"` to link only while holding ⌥.
     #[serde(default)]
     pub(crate) version_link: VersionLink,
+    /// Launcher profile IDs or instance names whose Minecraft version is kept in sync with a world.
+    #[serde(default)]
+    pub(crate) version_match: BTreeMap<String, VersionMatch>,
+    /// Problems with the config file which don't prevent loading it, such as unknown keys. These are shown in the menu, see also the `check_config` command.
+    #[serde(skip)]
+    pub(crate) warnings: Vec<String>,
+    /// Players who are listed first and marked with a star.
     #[serde(default)]
-    pub(crate) version_match: BTreeMap<String, String>,
-    #[serde(default = "One::one")]
-    pub(crate) zoom: u8,
+    pub(crate) watched_players: Vec<Uid>,
+    /// The scale factor for the menu bar icon and avatars, e.g. `2` for Retina displays. Detected automatically if omitted, see [`Config::zoom`].
+    #[serde(default)]
+    pub(crate) zoom: Option<u8>,
+}
+
+/// Where the value of a config entry came from, see [`Config::load_with_sources`].
+#[derive(Debug, Clone)]
+pub(crate) enum ConfigSource {
+    File(PathBuf),
+    /// The name of the environment variable.
+    Env(String),
+}
+
+impl fmt::Display for ConfigSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::File(path) => write!(f, "{}", path.display()),
+            Self::Env(var) => write!(f, "the {var} environment variable"),
+        }
+    }
 }
 
 impl Config {
-    pub(crate) fn load() -> Result<Self, Error> {
-        Ok(if let Some(path) = BaseDirectories::new().find_config_file("bitbar/plugins/wurstmineberg.json") {
-            serde_json::from_reader(File::open(path)?)?
+    /// The environment variable which overrides the given top-level config entry, e.g. `WURSTMINEBERG_SHOW_IF_EMPTY` for `showIfEmpty`.
+    pub(crate) fn env_var_name(key: &str) -> String {
+        let mut var = format!("WURSTMINEBERG_");
+        for c in key.chars() {
+            if c.is_ascii_uppercase() { var.push('_') }
+            var.push(c.to_ascii_uppercase());
+        }
+        var
+    }
+
+    /// The path to the config file, if it exists. This is `wurstmineberg.json` or `wurstmineberg.toml`, but not both.
+    pub(crate) fn path() -> Result<Option<PathBuf>, Error> {
+        let dirs = BaseDirectories::new();
+        match (dirs.find_config_file("bitbar/plugins/wurstmineberg.json"), dirs.find_config_file("bitbar/plugins/wurstmineberg.toml")) {
+            (Some(json_path), Some(toml_path)) => Err(Error::MultipleConfigFiles(json_path, toml_path)),
+            (Some(path), None) | (None, Some(path)) => Ok(Some(path)),
+            (None, None) => Ok(None),
+        }
+    }
+
+    /// Parses the config file at the given path as JSON or TOML, depending on its extension.
+    pub(crate) fn read_file(path: &Path) -> Result<Json, Error> {
+        Ok(if path.extension().is_some_and(|extension| extension == "toml") {
+            toml::from_str(&fs::read_to_string(path)?).map_err(|e| Error::ConfigToml(path.to_owned(), e))?
         } else {
-            Self::default()
+            serde_json::from_reader(File::open(path)?).map_err(|e| Error::ConfigJson(path.to_owned(), e))?
         })
     }
+
+    pub(crate) fn load() -> Result<Self, Error> {
+        Ok(Self::load_with_sources()?.0)
+    }
+
+    /// Loads the config file, with top-level entries overridden by `WURSTMINEBERG_*` environment variables.
+    ///
+    /// Also returns where each top-level entry which isn't using its default value came from.
+    /// Unknown keys in the config file are ignored, but noted in [`Config::warnings`].
+    pub(crate) fn load_with_sources() -> Result<(Self, BTreeMap<String, ConfigSource>), Error> {
+        let mut sources = BTreeMap::default();
+        let mut warnings = Vec::default();
+        let mut entries = if let Some(path) = Self::path()? {
+            match Self::read_file(&path)? {
+                Json::Object(entries) => {
+                    warnings = check::unknown_keys(&Json::Object(entries.clone()));
+                    for key in entries.keys() {
+                        sources.insert(key.clone(), ConfigSource::File(path.clone()));
+                    }
+                    entries
+                }
+                _ => return Err(Error::ConfigNotObject(path)),
+            }
+        } else {
+            serde_json::Map::default()
+        };
+        for &key in struct_fields::<Self>() {
+            let var = Self::env_var_name(key);
+            if let Some(value) = env::var_os(&var) {
+                let value = value.into_string().map_err(|_| Error::EnvVarNotUnicode(var.clone()))?;
+                // values which aren't valid JSON, like most URLs and paths, are used as strings
+                entries.insert(key.to_owned(), serde_json::from_str(&value).unwrap_or(Json::String(value)));
+                sources.insert(key.to_owned(), ConfigSource::Env(var));
+            }
+        }
+        let mut config = serde_path_to_error::deserialize::<_, Self>(Json::Object(entries)).map_err(|e| {
+            let path = e.path().to_string();
+            let key = path.split(['.', '[']).next().unwrap_or_default();
+            Error::ConfigValue(sources.get(key).cloned(), path, e.into_inner())
+        })?;
+        config.warnings = warnings;
+        Ok((config, sources))
+    }
+
+    /// The scale factor for the menu bar icon and avatars: the `zoom` config entry if present, otherwise detected from the plugin host.
+    ///
+    /// SwiftBar and xbar don't expose the scale factor of the displays, and the menu bar is shown on all of them at once,
+    /// so when running in either of them (detected using the `SWIFTBAR` and `XBARDarkMode` environment variables), this is 2.
+    /// The images are tagged with a DPI matching their scale factor, so macOS displays them at the same size on every display,
+    /// sharp on high-DPI displays and downsampled on low-DPI ones. Other hosts, like Waybar, get 1.
+    pub(crate) fn zoom(&self) -> u8 {
+        self.zoom.unwrap_or_else(|| if env::var_os("SWIFTBAR").is_some() || env::var_os("XBARDarkMode").is_some() { 2 } else { 1 })
+    }
+
+    /// The servers to monitor. If none are configured, this is the Wurstmineberg server as configured by the top-level `apiBaseUrl` and `pingAddresses` entries.
+    pub(crate) fn servers(&self) -> Cow<'_, [ServerConfig]> {
+        if self.servers.is_empty() {
+            Cow::Owned(vec![ServerConfig {
+                name: DEFAULT_SERVER_NAME.to_owned(),
+                api_base_url: Some(self.api_base_url.clone()),
+                ignored_players: Vec::default(),
+                main_world: default_main_world(),
+                ping_addresses: self.ping_addresses.clone(),
+                site_url: self.site_url.clone(),
+            }])
+        } else {
+            Cow::Borrowed(&self.servers)
+        }
+    }
 }
 
 impl Default for Config {
     fn default() -> Config {
         Config {
+            api_base_url: default_api_base_url(),
+            avatar_concurrency: default_avatar_concurrency(),
+            avatar_max_age: default_avatar_max_age(),
+            count_watched_players: false,
             defer_specs: Vec::default(),
             ignored_players: Vec::default(),
+            launcher_profiles_path: None,
+            notify: None,
+            output: Output::default(),
+            ping_addresses: BTreeMap::default(),
+            recently_online_hours: default_recently_online_hours(),
+            servers: Vec::default(),
+            show_deferred: false,
             show_if_empty: false,
             show_if_offline: false,
+            show_if_unwatched: true,
             single_color: true,
+            site_url: None,
+            undefer_players: Vec::default(),
             version_link: VersionLink::Enabled,
             version_match: BTreeMap::default(),
-            zoom: 1,
+            warnings: Vec::default(),
+            watched_players: Vec::default(),
+            zoom: None,
+        }
+    }
+}
+
+/// A Minecraft server or community to monitor, see [`Config::servers`].
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ServerConfig {
+    /// The name shown as a header in the menu.
+    pub(crate) name: String,
+    /// The base URL of the server's Wurstmineberg-compatible API, if it has one.
+    #[serde(default)]
+    pub(crate) api_base_url: Option<Url>,
+    /// Players on this server who should not be listed, in addition to the top-level `ignoredPlayers`.
+    #[serde(default)]
+    pub(crate) ignored_players: Vec<Uid>,
+    /// The world whose status determines whether the server is offline.
+    #[serde(default = "default_main_world")]
+    pub(crate) main_world: String,
+    /// Addresses to query using Server List Ping instead of the API, keyed by world name.
+    #[serde(default)]
+    pub(crate) ping_addresses: BTreeMap<String, String>,
+    /// The root of the website that `apiBaseUrl` belongs to, used for links to people pages. Derived from `apiBaseUrl` if omitted.
+    #[serde(default)]
+    pub(crate) site_url: Option<Url>,
+}
+
+/// Which launcher a `versionMatch` entry refers to.
+#[derive(Debug, Default, Clone, Copy, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Launcher {
+    /// The official Minecraft launcher. The `versionMatch` key is a profile ID in `launcher_profiles.json`.
+    #[default]
+    Vanilla,
+    /// Prism Launcher or MultiMC. The `versionMatch` key is the name of an instance folder.
+    #[serde(alias = "multimc")]
+    Prism,
+}
+
+/// A `versionMatch` entry, either just a world name for the vanilla launcher or an object specifying the launcher.
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub(crate) enum VersionMatch {
+    World(String),
+    Detailed(VersionMatchDetails),
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct VersionMatchDetails {
+    /// The world whose version is selected in the launcher.
+    world: String,
+    #[serde(default)]
+    launcher: Launcher,
+    /// The Prism Launcher/MultiMC instances folder, if not the default Prism Launcher location.
+    #[serde(default)]
+    instances_path: Option<PathBuf>,
+}
+
+impl VersionMatch {
+    pub(crate) fn world(&self) -> &str {
+        match self {
+            Self::World(world) | Self::Detailed(VersionMatchDetails { world, .. }) => world,
+        }
+    }
+
+    pub(crate) fn launcher(&self) -> Launcher {
+        match self {
+            Self::World(_) => Launcher::Vanilla,
+            Self::Detailed(details) => details.launcher,
         }
     }
+
+    pub(crate) fn instances_path(&self) -> Option<&Path> {
+        match self {
+            Self::World(_) => None,
+            Self::Detailed(details) => details.instances_path.as_deref(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct NotifyConfig {
+    /// A notifier command, e.g. `["notify-send"]`. The notification title and body are appended as arguments. Defaults to SwiftBar notifications.
+    #[serde(default)]
+    pub(crate) command: Vec<String>,
+    /// Notify when players join.
+    #[serde(default = "make_true")]
+    pub(crate) join: bool,
+    /// Notify when players leave.
+    #[serde(default = "make_true")]
+    pub(crate) leave: bool,
+    /// If specified, only these players trigger notifications.
+    #[serde(default)]
+    pub(crate) players: Option<Vec<Uid>>,
+    /// If specified, only these worlds trigger notifications.
+    #[serde(default)]
+    pub(crate) worlds: Option<Vec<String>>,
 }
 
+/// Times until which worlds are muted, by server name and world name.
+pub(crate) type WorldDeferrals = BTreeMap<String, BTreeMap<String, DateTime<Utc>>>;
+
 #[derive(Debug, Default, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase", default)]
 pub(crate) struct Data {
     pub(crate) deferred: Option<DateTime<Utc>>,
+    /// Worlds muted using `defer_world`, by server name and world name, with the time until which they're muted.
+    pub(crate) deferred_worlds: WorldDeferrals,
+    /// The player lists of each world of each server as of the last run, used to detect joins and leaves.
+    pub(crate) online: BTreeMap<String, BTreeMap<String, Vec<Uid>>>,
 }
 
 impl Data {
+    /// The time until which the plugin is deferred, if that time is in the future.
+    pub(crate) fn active_deferral(&self) -> Option<DateTime<Utc>> {
+        self.deferred.filter(|&deferred| deferred >= Utc::now())
+    }
+
+    /// Worlds which are muted, by server name and world name, with the time until which they're muted.
+    pub(crate) fn active_world_deferrals(&self) -> WorldDeferrals {
+        let now = Utc::now();
+        self.deferred_worlds.iter()
+            .map(|(server_name, worlds)| (server_name.clone(), worlds.iter().filter(|&(_, &deferred)| deferred >= now).map(|(world_name, &deferred)| (world_name.clone(), deferred)).collect::<BTreeMap<_, _>>()))
+            .filter(|(_, worlds)| !worlds.is_empty())
+            .collect()
+    }
+
     pub(crate) fn load() -> Result<Self, Error> {
         Ok(if let Some(path) = BaseDirectories::new().find_data_file("bitbar/plugin-cache/wurstmineberg.json") {
             serde_json::from_reader(File::open(path)?)?
@@ -137,93 +494,267 @@ impl Data {
 
     pub(crate) fn save(&mut self) -> Result<(), Error> {
         let data_path = BaseDirectories::new().place_data_file("bitbar/plugin-cache/wurstmineberg.json")?;
-        serde_json::to_writer_pretty(File::create(data_path)?, &self)?;
+        write_atomic(&data_path, &serde_json::to_vec_pretty(&self)?)?;
         Ok(())
     }
 }
 
-#[derive(Debug, Default, Deserialize, Serialize)]
-#[serde(transparent)]
-pub(crate) struct Cache(BTreeMap<Uid, Vec<u8>>);
+/// The last successfully retrieved response from an API endpoint, used when the API can't be reached.
+///
+/// The file is only rewritten when the response changes. Otherwise, only its modification time is updated.
+#[derive(Deserialize, Serialize)]
+pub(crate) struct Snapshot<T> {
+    /// When the response was last retrieved. The file stores when it was last changed, the file's modification time is used if later.
+    pub(crate) retrieved: DateTime<Utc>,
+    pub(crate) value: T,
+}
 
-impl Cache {
-    pub(crate) fn load() -> Result<Self, Error> {
-        Ok(if let Some(path) = BaseDirectories::new().find_cache_file("bitbar/plugin/wurstmineberg/avatars.json") {
-            serde_json::from_reader(File::open(path)?)?
+/// The path of the snapshot of the given API endpoint of the given server, relative to the XDG data directory.
+fn snapshot_path(server: &str, endpoint: &str) -> String {
+    format!("bitbar/plugin-cache/wurstmineberg/{}/{endpoint}.json", path_component(server))
+}
+
+impl<T: DeserializeOwned> Snapshot<T> {
+    pub(crate) fn load(server: &str, endpoint: &str) -> Result<Option<Self>, Error> {
+        Ok(if let Some(path) = BaseDirectories::new().find_data_file(snapshot_path(server, endpoint)) {
+            let file = File::open(path)?;
+            let modified = DateTime::<Utc>::from(file.metadata()?.modified()?);
+            let mut snapshot = serde_json::from_reader::<_, Self>(BufReader::new(file))?;
+            snapshot.retrieved = snapshot.retrieved.max(modified);
+            Some(snapshot)
         } else {
-            Self::default()
+            None
         })
     }
+}
 
-    pub(crate) fn save(self) -> Result<(), Error> {
-        let path = BaseDirectories::new().place_cache_file("bitbar/plugin/wurstmineberg/avatars.json")?;
-        serde_json::to_writer(File::create(path)?, &self)?;
+impl<T: Serialize> Snapshot<T> {
+    pub(crate) fn save(server: &str, endpoint: &str, value: &T) -> Result<(), Error> {
+        let path = BaseDirectories::new().place_data_file(snapshot_path(server, endpoint))?;
+        let json = serde_json::to_value(value)?;
+        if let Ok(file) = File::open(&path)
+            && serde_json::from_reader::<_, Snapshot<Json>>(BufReader::new(file)).is_ok_and(|snapshot| snapshot.value == json)
+        {
+            File::options().write(true).open(&path)?.set_modified(SystemTime::now())?;
+        } else {
+            write_atomic(&path, &serde_json::to_vec(&Snapshot { retrieved: Utc::now(), value: json })?)?;
+        }
         Ok(())
     }
+}
 
-    pub(crate) async fn get_img(&mut self, client: &reqwest::Client, uid: Uid, _ /*zoom*/: u8) -> Result<Image, Error> {
-        Ok(match self.0.entry(uid.clone()) {
-            btree_map::Entry::Occupied(entry) => entry.get().into(),
-            btree_map::Entry::Vacant(entry) => (&entry.insert({
-                let AvatarInfo { url, fallbacks } = client.get(&format!("https://wurstmineberg.de/api/v3/person/{}/avatar.json", uid))
-                    .send().await?
-                    .error_for_status()?
-                    .json().await?;
-                let response = client.get(url)
-                    .send().await
-                    .map_err(Error::from)
-                    .and_then(|response| Ok(response.error_for_status()?));
-                let mut image = match response {
-                    Ok(response) => response.image().await,
-                    Err(e) => Err(e),
-                };
-                if image.is_err() {
-                    for AvatarInfo { url, .. } in fallbacks {
-                        if let Ok(response) = client.get(url).send().await.and_then(|response| response.error_for_status()) {
-                            if let Ok(new_image) = response.image().await {
-                                image = Ok(new_image);
-                                break
-                            }
-                        }
-                    }
-                }
-                let image = image?;
-                //TODO resize to 16 * zoom and write with DPI 72 * zoom, see https://github.com/image-rs/image/issues/911
-                let mut buf = Cursor::default();
-                image.resize_exact(16, 16, FilterType::Nearest).write_to(&mut buf, ImageFormat::Png)?;
-                buf.into_inner()
-            })).into(),
-        })
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct CacheEntry {
+    fetched: DateTime<Utc>,
+    /// The URL the avatar was downloaded from, which is compared to the current one when revalidating.
+    url: Option<Url>,
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+impl CacheEntry {
+    fn expired() -> Self {
+        Self { fetched: DateTime::<Utc>::UNIX_EPOCH, url: None, etag: None, last_modified: None }
     }
 }
 
-#[derive(Deserialize, Serialize)]
-pub(crate) struct LauncherData {
-    pub(crate) profiles: BTreeMap<String, LauncherProfile>,
-    #[serde(flatten)]
-    _extra: BTreeMap<String, Json>,
+fn avatar_path(server: &str, zoom: u8, uid: &Uid) -> String {
+    format!("bitbar/plugin/wurstmineberg/avatars/{zoom}x/{}/{}.png", path_component(server), path_component(&uid.to_string()))
 }
 
-impl LauncherData {
-    fn path() -> Result<PathBuf, Error> {
-        Ok(UserDirs::new().ok_or(Error::MissingHomeDir)?.home_dir().join("Library").join("Application Support").join("minecraft").join("launcher_profiles.json"))
-    }
+/// The format of `avatars/index.json`.
+#[derive(Deserialize)]
+struct IndexFile {
+    servers: BTreeMap<String, BTreeMap<u8, BTreeMap<Uid, CacheEntry>>>,
+}
 
-    pub(crate) fn load() -> Result<LauncherData, Error> {
-        Ok(serde_json::from_reader(File::open(Self::path()?)?)?)
+/// PNG data of player avatars, keyed by server name and player. See [`Cache::get_imgs`].
+pub(crate) type Avatars = HashMap<String, HashMap<Uid, Vec<u8>>>;
+
+/// Avatars resized for the menu, stored as individual PNG files per zoom level, server, and player, with an index of metadata used for revalidation.
+#[derive(Debug, Default)]
+pub(crate) struct Cache {
+    /// Cache entries by server name, zoom level, and player.
+    index: BTreeMap<String, BTreeMap<u8, BTreeMap<Uid, CacheEntry>>>,
+    index_modified: bool,
+}
+
+impl Cache {
+    pub(crate) fn load() -> Result<Self, Error> {
+        let dirs = BaseDirectories::new();
+        if let Some(path) = dirs.find_cache_file("bitbar/plugin/wurstmineberg/avatars/index.json") {
+            let IndexFile { servers } = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+            Ok(Self { index: servers, index_modified: false })
+        } else if let Some(legacy_path) = dirs.find_cache_file("bitbar/plugin/wurstmineberg/avatars.json") {
+            // the format used before avatars were stored as individual files, with all avatars at 1x and no metadata, so they're imported as expired
+            let legacy: BTreeMap<Uid, Vec<u8>> = serde_json::from_reader(File::open(&legacy_path)?)?;
+            let mut cache = Self::default();
+            for (uid, png) in legacy {
+                write_atomic(&dirs.place_cache_file(avatar_path(DEFAULT_SERVER_NAME, 1, &uid))?, &png)?;
+                cache.index.entry(DEFAULT_SERVER_NAME.to_owned()).or_default().entry(1).or_default().insert(uid, CacheEntry::expired());
+            }
+            cache.index_modified = true;
+            cache.save()?;
+            fs::remove_file(legacy_path)?;
+            Ok(cache)
+        } else {
+            Ok(Self::default())
+        }
     }
 
+    /// Writes the index if it was modified. Avatar files are written as soon as they are downloaded.
     pub(crate) fn save(&mut self) -> Result<(), Error> {
-        Ok(serde_json::to_writer_pretty(File::create(Self::path()?)?, &self)?)
+        if self.index_modified {
+            let path = BaseDirectories::new().place_cache_file("bitbar/plugin/wurstmineberg/avatars/index.json")?;
+            write_atomic(&path, &serde_json::to_vec(&json!({"servers": self.index}))?)?;
+            self.index_modified = false;
+        }
+        Ok(())
+    }
+
+    /// Removes the avatars of players for whom `keep` returns `false`, given the server name and player.
+    pub(crate) fn retain(&mut self, mut keep: impl FnMut(&str, &Uid) -> bool) -> Result<(), Error> {
+        let dirs = BaseDirectories::new();
+        for (server, zooms) in &mut self.index {
+            for (&zoom, avatars) in zooms {
+                for uid in avatars.keys().filter(|&uid| !keep(server, uid)).cloned().collect_vec() {
+                    avatars.remove(&uid);
+                    if let Some(path) = dirs.find_cache_file(avatar_path(server, zoom, &uid)) {
+                        fs::remove_file(path)?;
+                    }
+                    self.index_modified = true;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the avatars of the given players, keyed by server name and player, as PNG data. Downloads those which aren't cached and revalidates those older than `max_age`.
+    ///
+    /// Up to `concurrency` avatars are downloaded at the same time. If revalidating fails, e.g. because the API can't be reached, the outdated avatar is used.
+    /// Players whose avatar isn't cached and can't be downloaded are omitted, with the errors returned alongside the avatars.
+    pub(crate) async fn get_imgs<'a>(&mut self, players: impl IntoIterator<Item = (&'a str, &'a Client, Uid)>, zoom: u8, max_age: TimeDelta, concurrency: usize) -> Result<(Avatars, Vec<Error>), Error> {
+        let dirs = BaseDirectories::new();
+        let mut pngs = HashMap::<_, HashMap<_, _>>::default();
+        let mut seen = HashSet::new();
+        let mut to_fetch = Vec::default();
+        for (server, client, uid) in players {
+            if !seen.insert((server, uid.clone())) { continue }
+            // an index entry without a file, e.g. because the file was deleted by hand, is treated as not cached
+            let entry = self.index.get(server).and_then(|zooms| zooms.get(&zoom)).and_then(|avatars| avatars.get(&uid));
+            let cached = match (entry, dirs.find_cache_file(avatar_path(server, zoom, &uid))) {
+                (Some(entry), Some(path)) => Some((entry, fs::read(path)?)),
+                (_, _) => None,
+            };
+            match cached {
+                Some((entry, png)) if Utc::now() - entry.fetched < max_age => { pngs.entry(server.to_owned()).or_default().insert(uid, png); }
+                cached => to_fetch.push((server, client, uid, cached)),
+            }
+        }
+        let results = stream::iter(to_fetch)
+            .map(|(server, client, uid, cached)| async move {
+                let result = Self::fetch(client, &uid, zoom, cached.as_ref().map(|&(entry, _)| entry)).await;
+                (server, uid, cached.map(|(_, png)| png), result)
+            })
+            .buffer_unordered(concurrency.max(1))
+            .collect::<Vec<_>>().await;
+        let mut errors = Vec::default();
+        for (server, uid, cached_png, result) in results {
+            let avatars = self.index.entry(server.to_owned()).or_default().entry(zoom).or_default();
+            let png = match result {
+                Ok(Some((entry, png))) => match dirs.place_cache_file(avatar_path(server, zoom, &uid)).and_then(|path| write_atomic(&path, &png)) {
+                    Ok(()) => {
+                        avatars.insert(uid.clone(), entry);
+                        self.index_modified = true;
+                        png
+                    }
+                    Err(e) => {
+                        errors.push(e.into());
+                        continue
+                    }
+                },
+                Ok(None) => if let Some(png) = cached_png {
+                    if let Some(entry) = avatars.get_mut(&uid) { entry.fetched = Utc::now() }
+                    self.index_modified = true;
+                    png
+                } else {
+                    errors.push(Error::AvatarNotModified);
+                    continue
+                },
+                Err(e) => if let Some(png) = cached_png {
+                    png
+                } else {
+                    errors.push(e);
+                    continue
+                },
+            };
+            pngs.entry(server.to_owned()).or_default().insert(uid, png);
+        }
+        Ok((pngs, errors))
+    }
+
+    /// Downloads an avatar. Returns `None` if the server responds that the cached entry is still up to date.
+    async fn fetch(client: &Client, uid: &Uid, zoom: u8, cached: Option<&CacheEntry>) -> Result<Option<(CacheEntry, Vec<u8>)>, Error> {
+        let AvatarInfo { url, fallbacks } = client.avatar(uid).await?;
+        let mut request = client.http().get(url.clone());
+        let mut conditional = false;
+        if let Some(cached) = cached.filter(|cached| cached.url.as_ref() == Some(&url)) {
+            if let Some(ref etag) = cached.etag { request = request.header(IF_NONE_MATCH, etag); conditional = true }
+            if let Some(ref last_modified) = cached.last_modified { request = request.header(IF_MODIFIED_SINCE, last_modified); conditional = true }
+        }
+        let response = request.send().await
+            .map_err(Error::from)
+            .and_then(|response| Ok(response.error_for_status()?));
+        let mut fetched = match response {
+            Ok(response) if response.status() == StatusCode::NOT_MODIFIED => return if conditional { Ok(None) } else { Err(Error::AvatarNotModified) },
+            Ok(response) => {
+                let etag = header_value(&response, ETAG)?;
+                let last_modified = header_value(&response, LAST_MODIFIED)?;
+                response.image().await.map(|image| (image, url, etag, last_modified))
+            }
+            Err(e) => Err(e),
+        };
+        if fetched.is_err() {
+            for AvatarInfo { url, .. } in fallbacks {
+                if let Ok(response) = client.http().get(url.clone()).send().await.and_then(|response| response.error_for_status())
+                    && let Ok(new_image) = response.image().await
+                {
+                    fetched = Ok((new_image, url, None, None));
+                    break
+                }
+            }
+        }
+        let (image, url, etag, last_modified) = fetched?;
+        let size = 16 * u32::from(zoom);
+        let image = image.resize_exact(size, size, FilterType::Nearest).into_rgba8();
+        // the image crate can't write DPI metadata (see https://github.com/image-rs/image/issues/911) so use png directly
+        let mut buf = Vec::default();
+        let mut encoder = png::Encoder::new(&mut buf, size, size);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        // 72 DPI per zoom level so the avatar is displayed at 16pt
+        let pixels_per_meter = (72.0 * f64::from(zoom) / 0.0254).round() as u32;
+        encoder.set_pixel_dims(Some(png::PixelDimensions { xppu: pixels_per_meter, yppu: pixels_per_meter, unit: png::Unit::Meter }));
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&image)?;
+        writer.finish()?;
+        Ok(Some((CacheEntry { fetched: Utc::now(), url: Some(url), etag, last_modified }, buf)))
     }
 }
 
-#[derive(Deserialize, Serialize)]
-#[serde(rename_all = "camelCase")]
-pub(crate) struct LauncherProfile {
-    pub(crate) last_version_id: String,
-    #[serde(flatten)]
-    _extra: BTreeMap<String, Json>,
+fn header_value(response: &reqwest::Response, name: HeaderName) -> Result<Option<String>, Error> {
+    Ok(response.headers().get(name).map(|value| value.to_str()).transpose()?.map(str::to_owned))
 }
 
+fn default_api_base_url() -> Url { Url::parse(DEFAULT_API_BASE_URL).expect("failed to parse default API base URL") }
+
+fn default_avatar_concurrency() -> usize { 8 }
+
+fn default_avatar_max_age() -> u32 { 24 * 60 * 60 }
+
+fn default_main_world() -> String { MAIN_WORLD.to_owned() }
+
+fn default_recently_online_hours() -> u16 { 3 }
+
 fn make_true() -> bool { true }
@@ -0,0 +1,161 @@
+//! The session history, an append-only log of joins and leaves stored as JSON lines in the data directory.
+
+use {
+    std::{
+        collections::HashSet,
+        fs::{
+            File,
+            OpenOptions,
+        },
+        io::{
+            BufRead as _,
+            BufReader,
+            Write as _,
+        },
+        path::Path,
+    },
+    chrono::{
+        TimeDelta,
+        prelude::*,
+    },
+    serde::{
+        Deserialize,
+        Serialize,
+    },
+    xdg::BaseDirectories,
+    crate::{
+        Error,
+        notify::{
+            Event,
+            EventKind,
+        },
+        util::write_atomic,
+    },
+};
+
+const PATH: &str = "bitbar/plugin-cache/wurstmineberg/history.jsonl";
+
+/// How long entries are kept in the history, unless `recentlyOnlineHours` is longer. See [`prune`].
+pub(crate) fn retention() -> TimeDelta { TimeDelta::days(30) }
+
+/// A join or leave, along with the time the plugin noticed it.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub(crate) struct Entry {
+    pub(crate) time: DateTime<Utc>,
+    #[serde(flatten)]
+    pub(crate) event: Event,
+}
+
+/// Appends the given events to the history, all with the given time.
+pub(crate) fn record(time: DateTime<Utc>, events: &[Event]) -> Result<(), Error> {
+    if events.is_empty() { return Ok(()) }
+    let mut buf = Vec::default();
+    for event in events {
+        serde_json::to_writer(&mut buf, &Entry { time, event: event.clone() })?;
+        buf.push(b'\n');
+    }
+    let path = BaseDirectories::new().place_data_file(PATH)?;
+    // a single write so a concurrent run of the plugin can't interleave its lines with these
+    OpenOptions::new().create(true).append(true).open(path)?.write_all(&buf)?;
+    Ok(())
+}
+
+/// Reads the entire history, oldest entry first. Lines which can't be parsed, e.g. because a write was interrupted, are skipped with a warning.
+pub(crate) fn load() -> Result<Vec<Entry>, Error> {
+    let Some(path) = BaseDirectories::new().find_data_file(PATH) else { return Ok(Vec::default()) };
+    Ok(read(&path)?.0)
+}
+
+/// Returns the parsed entries and the number of lines which were skipped.
+fn read(path: &Path) -> Result<(Vec<Entry>, usize), Error> {
+    let mut entries = Vec::default();
+    let mut skipped = 0;
+    for (idx, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        if line.is_empty() { continue }
+        match serde_json::from_str(&line) {
+            Ok(entry) => entries.push(entry),
+            Err(e) => {
+                eprintln!("warning: skipping line {} of {}: {e}", idx + 1, path.display());
+                skipped += 1;
+            }
+        }
+    }
+    Ok((entries, skipped))
+}
+
+/// Reads the history like [`load`], without entries from before `since`, except for each player's last leave, which is needed for the last time a watched player was seen.
+///
+/// If `write` is `true` and any entries or unparsable lines were dropped, the history file is rewritten without them, so it doesn't keep growing.
+pub(crate) fn prune(since: DateTime<Utc>, write: bool) -> Result<Vec<Entry>, Error> {
+    let Some(path) = BaseDirectories::new().find_data_file(PATH) else { return Ok(Vec::default()) };
+    prune_at(&path, since, write)
+}
+
+fn prune_at(path: &Path, since: DateTime<Utc>, write: bool) -> Result<Vec<Entry>, Error> {
+    let (entries, skipped) = read(path)?;
+    let num_entries = entries.len();
+    let mut last_left = HashSet::new();
+    let mut entries = entries.into_iter()
+        .rev()
+        .filter(|entry| {
+            let is_last_leave = entry.event.kind == EventKind::Leave && last_left.insert(entry.event.uid.clone());
+            entry.time >= since || is_last_leave
+        })
+        .collect::<Vec<_>>();
+    entries.reverse();
+    if write && (skipped > 0 || entries.len() < num_entries) {
+        let mut buf = Vec::default();
+        for entry in &entries {
+            serde_json::to_writer(&mut buf, entry)?;
+            buf.push(b'\n');
+        }
+        // entries recorded by a concurrent run between reading and replacing the file are lost, which only affects the “Recently Online” submenu
+        write_atomic(path, &buf)?;
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        std::fs,
+        super::*,
+    };
+
+    fn line(time: &str, kind: &str, uid: &str) -> String {
+        format!(r#"{{"time":"{time}","kind":"{kind}","server":"Wurstmineberg","world":"wurstmineberg","uid":"{uid}"}}"#)
+    }
+
+    #[test]
+    fn skips_unparsable_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history.jsonl");
+        fs::write(&path, format!("{}\n{{\"time\":\"2030-01\n\n{}\n", line("2030-01-01T00:00:00Z", "join", "fenhl"), line("2030-01-01T01:00:00Z", "leave", "fenhl"))).unwrap();
+        let (entries, skipped) = read(&path).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(skipped, 1);
+    }
+
+    #[test]
+    fn prune_keeps_last_leaves() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history.jsonl");
+        let contents = [
+            line("2030-01-01T00:00:00Z", "join", "fenhl"),
+            line("2030-01-01T01:00:00Z", "leave", "fenhl"),
+            line("2030-01-01T02:00:00Z", "leave", "alice"),
+            line("2030-01-02T00:00:00Z", "join", "alice"),
+            line("2030-01-03T00:00:00Z", "join", "fenhl"),
+            line("2030-01-03T01:00:00Z", "leave", "fenhl"),
+        ].into_iter().map(|line| line + "\n").collect::<String>();
+        fs::write(&path, &contents).unwrap();
+        let since = "2030-01-02T00:00:00Z".parse().unwrap();
+        let times = |entries: Vec<Entry>| entries.into_iter().map(|entry| entry.time.to_rfc3339()).collect::<Vec<_>>();
+        let expected = ["2030-01-01T02:00:00+00:00", "2030-01-02T00:00:00+00:00", "2030-01-03T00:00:00+00:00", "2030-01-03T01:00:00+00:00"];
+        assert_eq!(times(prune_at(&path, since, false).unwrap()), expected);
+        assert_eq!(fs::read_to_string(&path).unwrap(), contents);
+        assert_eq!(times(prune_at(&path, since, true).unwrap()), expected);
+        assert_eq!(times(read(&path).unwrap().0), expected);
+    }
+}
@@ -0,0 +1,536 @@
+//! Keeping the Minecraft version selected in a launcher in sync with the version running on a world, see the `versionMatch` config entry.
+
+use {
+    std::{
+        collections::{
+            BTreeMap,
+            BTreeSet,
+            btree_map,
+        },
+        fmt,
+        io,
+        mem,
+        path::{
+            Path,
+            PathBuf,
+        },
+    },
+    directories::{
+        BaseDirs,
+        UserDirs,
+    },
+    serde::{
+        Deserialize,
+        Serialize,
+    },
+    serde_json::Value as Json,
+    bitbar_wurstmineberg_status::ModLoader,
+    crate::{
+        Error,
+        files::{
+            Config,
+            Launcher,
+            VersionMatch,
+        },
+        state::{
+            Server,
+            World,
+        },
+        util::FileVersion,
+    },
+};
+
+/// A launcher whose profiles or instances can be switched to a different Minecraft version.
+pub(crate) trait LauncherSync {
+    /// Selects the given Minecraft version and mod loader for the given profile or instance.
+    ///
+    /// Changes are only written to disk by [`LauncherSync::save`].
+    fn set_version(&mut self, profile_id: &str, version: &GameVersion) -> Result<VersionChange, Error>;
+
+    /// Checks that the given profile or instance exists, without modifying it.
+    fn check_profile(&mut self, profile_id: &str) -> Result<(), Error>;
+
+    /// Writes any changes made using [`LauncherSync::set_version`] to disk, returning any errors that occurred.
+    ///
+    /// Changes which couldn't be saved are kept, see [`LauncherSync::is_modified`].
+    fn save(&mut self) -> Vec<Error>;
+
+    /// Whether the given profile or instance has changes which haven't been saved.
+    fn is_modified(&self, profile_id: &str) -> bool;
+}
+
+/// The Minecraft version and, for modded worlds, the mod loader a launcher profile should use.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct GameVersion {
+    minecraft: String,
+    loader: Option<(ModLoader, String)>,
+}
+
+impl GameVersion {
+    /// The version running on the world. `None` if it's a modded world which doesn't publish its Minecraft version.
+    fn of_world(world: &World) -> Option<Self> {
+        Some(Self {
+            minecraft: world.version.clone().or_else(|| world.modloader.as_ref()?.minecraft_version.clone())?,
+            loader: world.modloader.as_ref().map(|modloader| (modloader.name, modloader.version.clone())),
+        })
+    }
+
+    /// The version ID in the official launcher, i.e. the name of the folder in `versions` created by the mod loader's installer.
+    fn vanilla_id(&self) -> String {
+        match self.loader {
+            None => self.minecraft.clone(),
+            Some((ModLoader::Fabric, ref loader_version)) => format!("fabric-loader-{loader_version}-{}", self.minecraft),
+            Some((ModLoader::Forge, ref loader_version)) => format!("{}-forge-{loader_version}", self.minecraft),
+            Some((ModLoader::NeoForge, ref loader_version)) => format!("neoforge-{loader_version}"),
+            Some((ModLoader::Quilt, ref loader_version)) => format!("quilt-loader-{loader_version}-{}", self.minecraft),
+        }
+    }
+}
+
+impl fmt::Display for GameVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.minecraft)?;
+        if let Some((loader, ref loader_version)) = self.loader {
+            write!(f, " ({loader} {loader_version})")?;
+        }
+        Ok(())
+    }
+}
+
+/// The outcome of syncing a `versionMatch` entry.
+#[derive(Debug)]
+pub(crate) struct VersionMatchReport {
+    pub(crate) profile_id: String,
+    pub(crate) world: String,
+    pub(crate) result: Result<VersionChange, Error>,
+}
+
+#[derive(Debug)]
+pub(crate) struct VersionChange {
+    /// The version which was selected in the launcher before syncing, in the launcher's format.
+    pub(crate) current: String,
+    /// The version running on the world, in the launcher's format.
+    pub(crate) target: String,
+    /// Whether the launcher file was changed. `false` if the versions already matched, in dry-run mode, or if saving failed.
+    pub(crate) updated: bool,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct SyncReport {
+    pub(crate) mappings: Vec<VersionMatchReport>,
+    /// Errors which occurred while writing launcher files. The changes to these files were not made.
+    pub(crate) save_errors: Vec<Error>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum LauncherKey {
+    Vanilla,
+    /// An index into the list of Prism Launcher instances folders.
+    Prism(usize),
+}
+
+/// Updates launcher profiles according to the `versionMatch` config entry. Errors only affect the entry they occurred for and are included in the report.
+pub(crate) fn sync(config: &Config, servers: &[Server], dry_run: bool) -> SyncReport {
+    let mut report = SyncReport::default();
+    let mut launchers = Launchers::default();
+    let mut keys = Vec::with_capacity(config.version_match.len());
+    for (profile_id, version_match) in &config.version_match {
+        let result = sync_one(config, servers, &mut launchers, profile_id, version_match);
+        let (key, result) = match result {
+            Ok((key, change)) => (Some(key), Ok(change)),
+            Err(e) => (None, Err(e)),
+        };
+        keys.push(key);
+        report.mappings.push(VersionMatchReport { profile_id: profile_id.clone(), world: version_match.world().to_owned(), result });
+    }
+    if !dry_run {
+        if let Some(ref mut vanilla) = launchers.vanilla {
+            report.save_errors.extend(vanilla.save());
+        }
+        for instances in &mut launchers.prism {
+            report.save_errors.extend(instances.save());
+        }
+    }
+    for (mapping, key) in report.mappings.iter_mut().zip(keys) {
+        if let (Ok(change), Some(key)) = (&mut mapping.result, key)
+            && (dry_run || launchers.by_key(key).is_some_and(|launcher| launcher.is_modified(&mapping.profile_id)))
+        {
+            change.updated = false;
+        }
+    }
+    report
+}
+
+fn sync_one(config: &Config, servers: &[Server], launchers: &mut Launchers, profile_id: &str, version_match: &VersionMatch) -> Result<(LauncherKey, VersionChange), Error> {
+    let world = servers.iter().find_map(|server| server.worlds.get(version_match.world())).ok_or_else(|| Error::UnknownWorldName(profile_id.to_owned(), version_match.world().to_owned()))?;
+    let target = GameVersion::of_world(world).ok_or(Error::ModdedVersion)?;
+    let (key, launcher) = launchers.get(config, version_match)?;
+    Ok((key, launcher.set_version(profile_id, &target)?))
+}
+
+/// Checks that the launcher profile or instance of each `versionMatch` entry exists, without modifying anything.
+pub(crate) fn check(config: &Config) -> Vec<(&str, Result<(), Error>)> {
+    let mut launchers = Launchers::default();
+    config.version_match.iter().map(|(profile_id, version_match)| (&**profile_id, launchers.get(config, version_match).and_then(|(_, launcher)| launcher.check_profile(profile_id)))).collect()
+}
+
+/// The launchers used by `versionMatch` entries. They're only loaded once they're needed, since the vanilla launcher may not be installed.
+#[derive(Default)]
+struct Launchers {
+    vanilla: Option<LauncherData>,
+    prism: Vec<PrismInstances>,
+}
+
+impl Launchers {
+    fn get(&mut self, config: &Config, version_match: &VersionMatch) -> Result<(LauncherKey, &mut dyn LauncherSync), Error> {
+        Ok(match version_match.launcher() {
+            Launcher::Vanilla => {
+                if self.vanilla.is_none() { self.vanilla = Some(LauncherData::load(config)?) }
+                (LauncherKey::Vanilla, self.vanilla.as_mut().expect("vanilla launcher data should have just been loaded") as &mut dyn LauncherSync)
+            }
+            Launcher::Prism => {
+                let instances = PrismInstances::new(version_match.instances_path().map(Path::to_owned))?;
+                let idx = if let Some(idx) = self.prism.iter().position(|existing| existing.path() == instances.path()) {
+                    idx
+                } else {
+                    self.prism.push(instances);
+                    self.prism.len() - 1
+                };
+                (LauncherKey::Prism(idx), &mut self.prism[idx] as &mut dyn LauncherSync)
+            }
+        })
+    }
+
+    /// The launcher with the given key, if it has been loaded.
+    fn by_key(&self, key: LauncherKey) -> Option<&dyn LauncherSync> {
+        match key {
+            LauncherKey::Vanilla => self.vanilla.as_ref().map(|vanilla| vanilla as &dyn LauncherSync),
+            LauncherKey::Prism(idx) => self.prism.get(idx).map(|instances| instances as &dyn LauncherSync),
+        }
+    }
+}
+
+/// The `launcher_profiles.json` file of the official Minecraft launcher.
+pub(crate) struct LauncherData {
+    /// The path this was loaded from, so it's saved back to the same file.
+    path: PathBuf,
+    version: FileVersion,
+    modified: bool,
+    profiles: LauncherProfiles,
+}
+
+impl LauncherData {
+    /// The `launcherProfilesPath` config entry, falling back to the vanilla launcher's default location for the current platform.
+    fn path(config: &Config) -> Result<PathBuf, Error> {
+        if let Some(ref path) = config.launcher_profiles_path { return Ok(path.clone()) }
+        Ok(Self::default_path(UserDirs::new().ok_or(Error::MissingHomeDir)?.home_dir()))
+    }
+
+    /// The vanilla launcher's default location for the current platform, given the user's home directory.
+    fn default_path(home: &Path) -> PathBuf {
+        #[cfg(target_os = "macos")] let minecraft_dir = home.join("Library").join("Application Support").join("minecraft");
+        #[cfg(windows)] let minecraft_dir = home.join("AppData").join("Roaming").join(".minecraft");
+        #[cfg(not(any(target_os = "macos", windows)))] let minecraft_dir = home.join(".minecraft");
+        minecraft_dir.join("launcher_profiles.json")
+    }
+
+    pub(crate) fn load(config: &Config) -> Result<LauncherData, Error> {
+        let path = Self::path(config)?;
+        let (buf, version) = FileVersion::read(&path)?;
+        Ok(LauncherData {
+            profiles: serde_json::from_slice(&buf)?,
+            modified: false,
+            path, version,
+        })
+    }
+}
+
+impl LauncherSync for LauncherData {
+    fn set_version(&mut self, profile_id: &str, version: &GameVersion) -> Result<VersionChange, Error> {
+        let launcher_profile = self.profiles.profiles.get_mut(profile_id).ok_or_else(|| Error::UnknownLauncherProfile(profile_id.to_owned()))?;
+        let target = version.vanilla_id();
+        let updated = launcher_profile.last_version_id != target;
+        if updated { self.modified = true }
+        Ok(VersionChange {
+            current: mem::replace(&mut launcher_profile.last_version_id, target.clone()),
+            target, updated,
+        })
+    }
+
+    fn check_profile(&mut self, profile_id: &str) -> Result<(), Error> {
+        if self.profiles.profiles.contains_key(profile_id) {
+            Ok(())
+        } else {
+            Err(Error::UnknownLauncherProfile(profile_id.to_owned()))
+        }
+    }
+
+    fn save(&mut self) -> Vec<Error> {
+        if !self.modified { return Vec::default() }
+        match serde_json::to_vec_pretty(&self.profiles).map_err(Error::from).and_then(|buf| self.version.write(&self.path, &buf)) {
+            Ok(()) => {
+                self.modified = false;
+                Vec::default()
+            }
+            Err(e) => vec![e],
+        }
+    }
+
+    fn is_modified(&self, _: &str) -> bool {
+        // all profiles are saved in the same file
+        self.modified
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+struct LauncherProfiles {
+    profiles: BTreeMap<String, LauncherProfile>,
+    #[serde(flatten)]
+    _extra: BTreeMap<String, Json>,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct LauncherProfile {
+    last_version_id: String,
+    #[serde(flatten)]
+    _extra: BTreeMap<String, Json>,
+}
+
+/// The instances folder of Prism Launcher or MultiMC, where each instance has an `mmc-pack.json` file listing its components.
+pub(crate) struct PrismInstances {
+    path: PathBuf,
+    packs: BTreeMap<String, (MmcPack, FileVersion)>,
+    /// Instances whose component lists have been modified but not yet saved.
+    modified: BTreeSet<String>,
+}
+
+impl PrismInstances {
+    /// Component UIDs whose version is the Minecraft version. Fabric's intermediary mappings are versioned this way and must match the game.
+    const VERSIONED_COMPONENTS: [&'static str; 2] = ["net.minecraft", "net.fabricmc.intermediary"];
+
+    /// The component UID of each mod loader.
+    fn loader_uid(loader: ModLoader) -> &'static str {
+        match loader {
+            ModLoader::Fabric => "net.fabricmc.fabric-loader",
+            ModLoader::Forge => "net.minecraftforge",
+            ModLoader::NeoForge => "net.neoforged",
+            ModLoader::Quilt => "org.quiltmc.quilt-loader",
+        }
+    }
+
+    /// The mod loader with the given component UID, if any.
+    fn loader(uid: &str) -> Option<ModLoader> {
+        [ModLoader::Fabric, ModLoader::Forge, ModLoader::NeoForge, ModLoader::Quilt].into_iter().find(|&loader| Self::loader_uid(loader) == uid)
+    }
+
+    /// Uses the given instances folder, or Prism Launcher's default location for the current platform.
+    pub(crate) fn new(path: Option<PathBuf>) -> Result<Self, Error> {
+        Ok(Self {
+            path: if let Some(path) = path { path } else { BaseDirs::new().ok_or(Error::MissingHomeDir)?.data_dir().join("PrismLauncher").join("instances") },
+            packs: BTreeMap::default(),
+            modified: BTreeSet::default(),
+        })
+    }
+
+    /// The component list of the given instance, loading it if necessary.
+    fn pack(&mut self, instance: &str) -> Result<&mut MmcPack, Error> {
+        let (pack, _) = match self.packs.entry(instance.to_owned()) {
+            btree_map::Entry::Occupied(entry) => entry.into_mut(),
+            btree_map::Entry::Vacant(entry) => entry.insert(match FileVersion::read(&self.path.join(instance).join("mmc-pack.json")) {
+                Ok((buf, file_version)) => (serde_json::from_slice(&buf)?, file_version),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Err(Error::UnknownLauncherProfile(instance.to_owned())),
+                Err(e) => return Err(e.into()),
+            }),
+        };
+        Ok(pack)
+    }
+
+    /// The folder this was created from, to allow reusing instances with the same path.
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl LauncherSync for PrismInstances {
+    fn set_version(&mut self, instance: &str, version: &GameVersion) -> Result<VersionChange, Error> {
+        let pack = self.pack(instance)?;
+        let current = GameVersion {
+            minecraft: pack.components.iter().find(|component| component.uid == "net.minecraft").ok_or_else(|| Error::MissingComponent(instance.to_owned(), "net.minecraft"))?
+                .version.clone().unwrap_or_default(),
+            loader: pack.components.iter().find_map(|component| Some((Self::loader(&component.uid)?, component.version.clone()?))),
+        };
+        // a mod loader installed in an instance for a vanilla world is left alone
+        if current.minecraft == version.minecraft && (version.loader.is_none() || current.loader == version.loader) {
+            return Ok(VersionChange { current: current.to_string(), target: current.to_string(), updated: false })
+        }
+        if let Some((loader, _)) = version.loader {
+            // adding a mod loader requires resolving its dependencies, which is left to Prism Launcher
+            if !pack.components.iter().any(|component| Self::loader(&component.uid) == Some(loader)) {
+                return Err(Error::MissingComponent(instance.to_owned(), Self::loader_uid(loader)))
+            }
+        }
+        for component in &mut pack.components {
+            let component_version = if Self::VERSIONED_COMPONENTS.contains(&&*component.uid) {
+                &version.minecraft
+            } else if let Some((loader, ref loader_version)) = version.loader && Self::loader(&component.uid) == Some(loader) {
+                loader_version
+            } else {
+                continue
+            };
+            component.version = Some(component_version.clone());
+            // Prism Launcher regenerates the cached metadata, but would show the stale version until then
+            if let Some(Json::String(cached_version)) = component.extra.get_mut("cachedVersion") {
+                *cached_version = component_version.clone();
+            }
+        }
+        self.modified.insert(instance.to_owned());
+        Ok(VersionChange { current: current.to_string(), target: version.to_string(), updated: true })
+    }
+
+    fn check_profile(&mut self, instance: &str) -> Result<(), Error> {
+        self.pack(instance)?;
+        Ok(())
+    }
+
+    fn save(&mut self) -> Vec<Error> {
+        let mut errors = Vec::default();
+        // each instance has its own file, so an error only affects that instance
+        for instance in mem::take(&mut self.modified) {
+            let (pack, version) = self.packs.get_mut(&instance).expect("modified instance should have been loaded");
+            if let Err(e) = serde_json::to_vec_pretty(&*pack).map_err(Error::from).and_then(|buf| version.write(&self.path.join(&instance).join("mmc-pack.json"), &buf)) {
+                errors.push(e);
+                self.modified.insert(instance);
+            }
+        }
+        errors
+    }
+
+    fn is_modified(&self, instance: &str) -> bool {
+        self.modified.contains(instance)
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+struct MmcPack {
+    components: Vec<MmcComponent>,
+    #[serde(flatten)]
+    _extra: BTreeMap<String, Json>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct MmcComponent {
+    uid: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    version: Option<String>,
+    #[serde(flatten)]
+    extra: BTreeMap<String, Json>,
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        std::fs,
+        super::*,
+    };
+
+    const LAUNCHER_PROFILES: &str = r#"{
+        "profiles": {
+            "wurstmineberg": {"lastVersionId": "1.21.3", "name": "Wurstmineberg"}
+        },
+        "version": 3
+    }"#;
+
+    fn vanilla(minecraft: &str) -> GameVersion {
+        GameVersion { minecraft: minecraft.to_owned(), loader: None }
+    }
+
+    #[test]
+    fn default_launcher_profiles_path() {
+        let home = tempfile::tempdir().unwrap();
+        let path = LauncherData::default_path(home.path());
+        #[cfg(target_os = "macos")] assert_eq!(path.parent().unwrap(), home.path().join("Library").join("Application Support").join("minecraft"));
+        #[cfg(windows)] assert_eq!(path.parent().unwrap(), home.path().join("AppData").join("Roaming").join(".minecraft"));
+        #[cfg(not(any(target_os = "macos", windows)))] assert_eq!(path.parent().unwrap(), home.path().join(".minecraft"));
+        assert_eq!(path.file_name().unwrap(), "launcher_profiles.json");
+    }
+
+    #[test]
+    fn launcher_profiles_path_override() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("launcher_profiles.json");
+        fs::write(&path, LAUNCHER_PROFILES).unwrap();
+        let config = Config { launcher_profiles_path: Some(path.clone()), ..Config::default() };
+        assert_eq!(LauncherData::path(&config).unwrap(), path);
+        let mut launcher = LauncherData::load(&config).unwrap();
+        launcher.check_profile("wurstmineberg").unwrap();
+        assert!(matches!(launcher.check_profile("other"), Err(Error::UnknownLauncherProfile(_))));
+        let change = launcher.set_version("wurstmineberg", &vanilla("1.21.4")).unwrap();
+        assert_eq!((&*change.current, &*change.target, change.updated), ("1.21.3", "1.21.4", true));
+        assert!(launcher.save().is_empty());
+        assert!(!launcher.is_modified("wurstmineberg"));
+        let saved = serde_json::from_slice::<Json>(&fs::read(&path).unwrap()).unwrap();
+        assert_eq!(saved["profiles"]["wurstmineberg"]["lastVersionId"], "1.21.4");
+        // unknown entries are kept
+        assert_eq!(saved["profiles"]["wurstmineberg"]["name"], "Wurstmineberg");
+        assert_eq!(saved["version"], 3);
+    }
+
+    #[test]
+    fn launcher_profiles_changed_externally() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("launcher_profiles.json");
+        fs::write(&path, LAUNCHER_PROFILES).unwrap();
+        let config = Config { launcher_profiles_path: Some(path.clone()), ..Config::default() };
+        let mut launcher = LauncherData::load(&config).unwrap();
+        launcher.set_version("wurstmineberg", &vanilla("1.21.4")).unwrap();
+        fs::write(&path, LAUNCHER_PROFILES.replace("1.21.3", "1.20.1")).unwrap();
+        assert!(matches!(&*launcher.save(), [Error::FileChanged(_)]));
+        assert!(launcher.is_modified("wurstmineberg"));
+        assert!(fs::read_to_string(&path).unwrap().contains("1.20.1"));
+    }
+
+    #[test]
+    fn prism_instances_path() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("Wurstmineberg")).unwrap();
+        fs::write(dir.path().join("Wurstmineberg").join("mmc-pack.json"), r#"{
+            "components": [
+                {"uid": "net.minecraft", "version": "1.21.3", "cachedVersion": "1.21.3"},
+                {"uid": "net.fabricmc.intermediary", "version": "1.21.3"},
+                {"uid": "net.fabricmc.fabric-loader", "version": "0.16.9"}
+            ],
+            "formatVersion": 1
+        }"#).unwrap();
+        let mut instances = PrismInstances::new(Some(dir.path().to_owned())).unwrap();
+        assert_eq!(instances.path(), dir.path());
+        assert!(matches!(instances.check_profile("other"), Err(Error::UnknownLauncherProfile(_))));
+        let change = instances.set_version("Wurstmineberg", &GameVersion { minecraft: format!("1.21.4"), loader: Some((ModLoader::Fabric, format!("0.16.10"))) }).unwrap();
+        assert!(change.updated);
+        assert!(instances.save().is_empty());
+        let saved = serde_json::from_slice::<Json>(&fs::read(dir.path().join("Wurstmineberg").join("mmc-pack.json")).unwrap()).unwrap();
+        assert_eq!(saved["components"][0]["version"], "1.21.4");
+        assert_eq!(saved["components"][0]["cachedVersion"], "1.21.4");
+        assert_eq!(saved["components"][1]["version"], "1.21.4");
+        assert_eq!(saved["components"][2]["version"], "0.16.10");
+        assert_eq!(saved["formatVersion"], 1);
+    }
+
+    #[test]
+    fn prism_save_continues_after_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let pack = r#"{"components": [{"uid": "net.minecraft", "version": "1.21.3"}]}"#;
+        for instance in ["a", "b"] {
+            fs::create_dir(dir.path().join(instance)).unwrap();
+            fs::write(dir.path().join(instance).join("mmc-pack.json"), pack).unwrap();
+        }
+        let mut instances = PrismInstances::new(Some(dir.path().to_owned())).unwrap();
+        instances.set_version("a", &vanilla("1.21.4")).unwrap();
+        instances.set_version("b", &vanilla("1.21.4")).unwrap();
+        fs::write(dir.path().join("a").join("mmc-pack.json"), pack.replace("1.21.3", "1.20.1")).unwrap();
+        assert!(matches!(&*instances.save(), [Error::FileChanged(_)]));
+        assert!(instances.is_modified("a"));
+        assert!(!instances.is_modified("b"));
+        assert!(fs::read_to_string(dir.path().join("b").join("mmc-pack.json")).unwrap().contains("1.21.4"));
+    }
+}
@@ -0,0 +1,160 @@
+//! A typed client for the [Wurstmineberg](https://wurstmineberg.de/) API, used by the BitBar plugin in this package.
+
+use {
+    std::{
+        collections::BTreeMap,
+        io,
+    },
+    serde::de::DeserializeOwned,
+    url::Url,
+    wheel::traits::ReqwestResponseExt as _,
+};
+pub use crate::model::*;
+// used in the binary only
+use {
+    async_trait as _,
+    chrono as _,
+    directories as _,
+    futures as _,
+    image as _,
+    itertools as _,
+    mime as _,
+    png as _,
+    serde_path_to_error as _,
+    timespec as _,
+    toml as _,
+    xdg as _,
+};
+
+// used in the binary's tests only
+#[cfg(test)] use tempfile as _;
+
+mod model;
+pub mod ping;
+
+pub const DEFAULT_API_BASE_URL: &str = "https://wurstmineberg.de/api/v3/";
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)] Io(#[from] io::Error),
+    #[error(transparent)] Json(#[from] serde_json::Error),
+    #[error(transparent)] Reqwest(#[from] reqwest::Error),
+    #[error(transparent)] Url(#[from] url::ParseError),
+    #[error(transparent)] Wheel(#[from] wheel::Error),
+    #[error("invalid server address: {0}")]
+    InvalidAddress(String),
+    #[error("server list ping response is too long")]
+    PacketTooLong,
+    #[error("server did not respond to server list ping within 10 seconds")]
+    PingTimeout,
+    #[error("unexpected packet ID in server list ping response: {0:#04x}")]
+    UnexpectedPacketId(i32),
+    #[error("server list ping response contains an invalid VarInt")]
+    VarIntTooLong,
+}
+
+/// A client for the Wurstmineberg API.
+#[derive(Debug, Clone)]
+pub struct Client {
+    http: reqwest::Client,
+    api_base_url: Url,
+    site_url: Option<Url>,
+}
+
+impl Client {
+    /// Creates a client for the API at the given base URL.
+    ///
+    /// A trailing slash is added to the base URL if missing, so endpoint paths are joined onto it instead of replacing its last path segment.
+    pub fn new(http: reqwest::Client, mut api_base_url: Url) -> Self {
+        if !api_base_url.path().ends_with('/') {
+            let path = format!("{}/", api_base_url.path());
+            api_base_url.set_path(&path);
+        }
+        Self { http, api_base_url, site_url: None }
+    }
+
+    /// Sets the root of the website that the API is hosted on, instead of deriving it from the API base URL.
+    pub fn with_site_url(mut self, site_url: Url) -> Self {
+        self.site_url = Some(site_url);
+        self
+    }
+
+    /// The underlying HTTP client, e.g. for downloading avatar images.
+    pub fn http(&self) -> &reqwest::Client {
+        &self.http
+    }
+
+    pub fn api_base_url(&self) -> &Url {
+        &self.api_base_url
+    }
+
+    /// The root of the website that the API is hosted on, e.g. for links to people pages.
+    ///
+    /// Unless set using [`Client::with_site_url`], this is the API base URL with everything from its last `api` path segment removed,
+    /// e.g. `https://wurstmineberg.de/` for `https://wurstmineberg.de/api/v3/`, or the root of the API's host if there is no such segment.
+    pub fn site_url(&self) -> Url {
+        if let Some(ref site_url) = self.site_url { return site_url.clone() }
+        let mut site_url = self.api_base_url.clone();
+        let path = self.api_base_url.path();
+        site_url.set_path(path.rfind("/api/").map_or("/", |idx| &path[..=idx]));
+        site_url.set_query(None);
+        site_url.set_fragment(None);
+        site_url
+    }
+
+    /// The website's profile page of the given person, or `None` for a player who isn't a Wurstmineberg person.
+    pub fn person_url(&self, uid: &Uid) -> Result<Option<Url>, Error> {
+        Ok(if uid.is_person() { Some(self.site_url().join(&format!("people/{uid}"))?) } else { None })
+    }
+
+    async fn get<T: DeserializeOwned>(&self, path: &str, query: &[(&str, &str)]) -> Result<T, Error> {
+        Ok(
+            self.http.get(self.api_base_url.join(path)?)
+                .query(query)
+                .send().await?
+                .detailed_error_for_status().await?
+                .json_with_text_in_error().await?
+        )
+    }
+
+    /// The status of each world, including the list of online players.
+    pub async fn worlds(&self) -> Result<BTreeMap<String, Status>, Error> {
+        self.get("server/worlds.json", &[("list", "1")]).await
+    }
+
+    pub async fn people(&self) -> Result<People, Error> {
+        self.get("people.json", &[]).await
+    }
+
+    pub async fn person(&self, uid: &Uid) -> Result<Person, Error> {
+        self.get(&format!("person/{uid}.json"), &[]).await
+    }
+
+    pub async fn avatar(&self, uid: &Uid) -> Result<AvatarInfo, Error> {
+        self.get(&format!("person/{uid}/avatar.json"), &[]).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn site_url(api_base_url: &str) -> Url {
+        Client::new(reqwest::Client::default(), api_base_url.parse().unwrap()).site_url()
+    }
+
+    #[test]
+    fn site_url_derived_from_api_base_url() {
+        assert_eq!(site_url(DEFAULT_API_BASE_URL).as_str(), "https://wurstmineberg.de/");
+        assert_eq!(site_url("https://example.com/mc/api/v2").as_str(), "https://example.com/mc/");
+        assert_eq!(site_url("http://localhost:8080/api/").as_str(), "http://localhost:8080/");
+        assert_eq!(site_url("http://localhost:8080/v3/?key=x").as_str(), "http://localhost:8080/");
+    }
+
+    #[test]
+    fn site_url_override() {
+        let client = Client::new(reqwest::Client::default(), DEFAULT_API_BASE_URL.parse().unwrap()).with_site_url("https://example.com/".parse().unwrap());
+        assert_eq!(client.person_url(&Uid::WmbId(format!("fenhl"))).unwrap().unwrap().as_str(), "https://example.com/people/fenhl");
+        assert_eq!(client.person_url(&Uid::Minecraft { minecraft: format!("Notch") }).unwrap(), None);
+    }
+}
@@ -1,74 +1,113 @@
 use {
     std::{
-        borrow::Cow,
         collections::{
-            BTreeMap,
             HashMap,
+            btree_map,
+            hash_map,
         },
         convert::Infallible,
-        env,
         io,
+        path::PathBuf,
+        process::ExitStatus,
         time::Duration,
     },
     bitbar::{
         ContentItem,
         Menu,
         MenuItem,
-        attr::{
-            Command,
-            Image,
-        },
+        attr::Image,
     },
     chrono::prelude::*,
     css_color_parser::ColorParseError,
     image::ImageError,
-    itertools::Itertools as _,
     mime::Mime,
-    serde::Deserialize,
-    url::Url,
-    wheel::traits::ReqwestResponseExt as _,
+    serenity as _, // used in the library
+    bitbar_wurstmineberg_status::People,
     crate::{
         files::{
-            Cache,
             Config,
+            ConfigSource,
             Data,
-            LauncherData,
-            VersionLink,
+            Snapshot,
         },
-        model::*,
+        output::Output,
+        state::State,
         util::ResultNeverExt as _,
     },
 };
 
+mod check;
 mod files;
-mod model;
+mod history;
+mod launcher;
+mod notify;
+mod output;
+mod state;
 mod util;
 
 const MAIN_WORLD: &str = "wurstmineberg";
+/// The name of the server monitored if the `servers` config entry is not specified.
+const DEFAULT_SERVER_NAME: &str = "Wurstmineberg";
 
 #[derive(Debug, thiserror::Error)]
 enum Error {
+    #[error(transparent)] Api(#[from] bitbar_wurstmineberg_status::Error),
     #[error(transparent)] ColorParse(#[from] ColorParseError),
     #[error(transparent)] Header(#[from] reqwest::header::ToStrError),
     #[error(transparent)] Image(#[from] ImageError),
     #[error(transparent)] Io(#[from] io::Error),
     #[error(transparent)] Json(#[from] serde_json::Error),
     #[error(transparent)] MimeFromStr(#[from] mime::FromStrError),
+    #[error(transparent)] Png(#[from] png::EncodingError),
     #[error(transparent)] Reqwest(#[from] reqwest::Error),
     #[error(transparent)] Timespec(#[from] timespec::Error),
     #[error(transparent)] Url(#[from] url::ParseError),
     #[error(transparent)] Wheel(#[from] wheel::Error),
+    #[error("server responded with 304 Not Modified to an unconditional request for an avatar")]
+    AvatarNotModified,
+    #[error("{0} command exited with {1}")]
+    CommandExit(&'static str, ExitStatus),
     #[error("BitBar command should have 1–6 parameters including the command name, but this one has {0}")]
     CommandLength(usize),
+    #[error("failed to parse config file at {}: {}", .0.display(), .1)]
+    ConfigJson(PathBuf, serde_json::Error),
+    #[error("the config file at {} is not an object", .0.display())]
+    ConfigNotObject(PathBuf),
+    #[error("found {0} problem(s) in the config")]
+    ConfigProblems(usize),
+    #[error("failed to parse config file at {}: {}", .0.display(), .1)]
+    ConfigToml(PathBuf, toml::de::Error),
+    #[error("invalid value for config entry {}{}: {}", .1, .0.as_ref().map(|source| format!(" from {source}")).unwrap_or_default(), .2)]
+    ConfigValue(Option<ConfigSource>, String, serde_json::Error),
+    #[error("the environment variable {0} is not valid Unicode")]
+    EnvVarNotUnicode(String),
     #[error("given timespec matches no dates")]
     EmptyTimespec,
+    #[error("{} was modified by another program, not overwriting it", .0.display())]
+    FileChanged(PathBuf),
     #[error("{0} is not a known image MIME type")]
     InvalidMime(Mime),
+    #[error("missing value for argument {0}")]
+    MissingArgumentValue(String),
+    #[error("Prism Launcher instance “{0}” has no {1} component")]
+    MissingComponent(String, &'static str),
     #[error("could not find your user folder")]
     MissingHomeDir,
-    #[error("attempted to check version of modded Minecraft world")]
+    #[error("missing server name")]
+    MissingServerName,
+    #[error("missing world name")]
+    MissingWorldName,
+    #[error("attempted to check version of modded Minecraft world which doesn't publish its Minecraft version")]
     ModdedVersion,
-    #[error("no profile named “{0}” in launcher data")]
+    #[error("found both {} and {}, remove one of them", .0.display(), .1.display())]
+    MultipleConfigFiles(PathBuf, PathBuf),
+    #[error("server “{0}” has neither an API base URL nor ping addresses")]
+    NoStatusSource(String),
+    #[error("{0} (the snapshot of the last successful response could not be loaded either: {1})")]
+    SnapshotFallback(Box<Error>, Box<Error>),
+    #[error("unknown argument: {0}")]
+    UnknownArgument(String),
+    #[error("no launcher profile or instance named “{0}”")]
     UnknownLauncherProfile(String),
     #[error("unknown world name “{1}” in versionMatch config for profile {0}")]
     UnknownWorldName(String, String),
@@ -84,7 +123,7 @@ impl From<Error> for Menu {
     fn from(e: Error) -> Menu {
         let mut error_menu = Vec::default();
         match e {
-            Error::Reqwest(e) => {
+            Error::Reqwest(e) | Error::Api(bitbar_wurstmineberg_status::Error::Reqwest(e)) => {
                 error_menu.push(MenuItem::new(format!("reqwest error: {e}")));
                 if let Some(url) = e.url() {
                     error_menu.push(ContentItem::new(format!("URL: {url}"))
@@ -106,53 +145,6 @@ impl From<Error> for Menu {
     }
 }
 
-#[derive(Debug, Deserialize)]
-struct Status {
-    #[serde(default)]
-    list: Vec<Uid>,
-    running: bool,
-    version: Option<String>,
-}
-
-impl Status {
-    async fn load(client: &reqwest::Client) -> Result<BTreeMap<String, Status>, Error> {
-        Ok(
-            client.get("https://wurstmineberg.de/api/v3/server/worlds.json")
-                .query(&[("list", "1")])
-                .send().await?
-                .detailed_error_for_status().await?
-                .json_with_text_in_error().await?
-        )
-    }
-}
-
-#[derive(Debug, Deserialize)]
-struct People {
-    people: HashMap<Uid, Person>,
-}
-
-impl People {
-    async fn load(client: &reqwest::Client) -> Result<People, Error> {
-        Ok(
-            client.get("https://wurstmineberg.de/api/v3/people.json")
-                .send().await?
-                .detailed_error_for_status().await?
-                .json_with_text_in_error().await?
-        )
-    }
-
-    fn get(&self, uid: impl Into<Uid>) -> Option<&Person> {
-        self.people.get(&uid.into())
-    }
-}
-
-#[derive(Debug, Deserialize)]
-struct AvatarInfo {
-    url: Url,
-    #[serde(default)]
-    fallbacks: Vec<AvatarInfo>,
-}
-
 fn wurstpick(zoom: u8) -> Image {
     if zoom >= 2 {
         Image::template(&include_bytes!("../assets/wurstpick-2x.png")[..]).never_unwrap()
@@ -170,118 +162,200 @@ fn defer(timespec: Vec<String>) -> Result<(), Error> {
     Ok(())
 }
 
-#[bitbar::main(
-    error_template_image = "../assets/wurstpick-2x.png", //TODO use wurstpick.png for low-DPI screens?
-    commands(defer),
-)]
-async fn main() -> Result<Menu, Error> {
-    let current_exe = env::current_exe()?;
-    let client = reqwest::Client::builder()
-        .user_agent(concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION")))
-        .timeout(Duration::from_secs(30))
-        .use_rustls_tls()
-        .build()?;
+#[bitbar::command]
+fn undefer() -> Result<(), Error> {
+    let mut data = Data::load()?;
+    data.deferred = None;
+    data.save()?;
+    Ok(())
+}
+
+/// Mutes a single world until the given timespec. The first two arguments are the names of the server and the world.
+///
+/// The timespec may also be given as a single argument with its words separated by spaces, which the “Mute World” menu items use to stay within the limit on command parameters.
+#[bitbar::command(varargs)]
+fn defer_world(args: Vec<String>) -> Result<(), Error> {
+    let mut args = args.into_iter();
+    let server_name = args.next().ok_or(Error::MissingServerName)?;
+    let world_name = args.next().ok_or(Error::MissingWorldName)?;
+    let timespec = args.flat_map(|arg| arg.split_whitespace().map(str::to_owned).collect::<Vec<_>>()).collect::<Vec<_>>();
+    if timespec.is_empty() { return Err(Error::EmptyTimespec) }
+    let mut data = Data::load()?;
+    data.deferred_worlds.entry(server_name).or_default().insert(world_name, timespec::next(timespec)?.ok_or(Error::EmptyTimespec)?);
+    data.save()?;
+    Ok(())
+}
+
+/// Unmutes a world muted using `defer_world`.
+#[bitbar::command]
+fn undefer_world(server_name: String, world_name: String) -> Result<(), Error> {
+    let mut data = Data::load()?;
+    if let btree_map::Entry::Occupied(mut worlds) = data.deferred_worlds.entry(server_name)
+        && worlds.get_mut().remove(&world_name).is_some()
+    {
+        if worlds.get().is_empty() { worlds.remove(); }
+        data.save()?;
+    }
+    Ok(())
+}
+
+/// Prints the time until which the plugin is deferred, if any, and which worlds are muted.
+#[bitbar::command]
+fn defer_status() -> Result<(), Error> {
     let data = Data::load()?;
-    if data.deferred.map_or(false, |deferred| deferred >= Utc::now()) {
-        return Ok(Menu::default())
+    if let Some(deferred) = data.active_deferral() {
+        println!("deferred until {}", deferred.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S"));
+    } else {
+        println!("not deferred");
     }
-    let config = Config::load()?;
-    let mut statuses = Status::load(&client).await?;
-    for status in statuses.values_mut() {
-        status.list.retain(|uid| !config.ignored_players.contains(uid));
+    for (server_name, worlds) in data.active_world_deferrals() {
+        for (world_name, deferred) in worlds {
+            println!("{world_name} on {server_name} muted until {}", deferred.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S"));
+        }
     }
-    if !config.version_match.is_empty() {
-        let mut launcher_data = LauncherData::load()?;
-        let mut modified = false;
-        for (profile_id, world_name) in config.version_match {
-            let launcher_profile = launcher_data.profiles.get_mut(&profile_id).ok_or_else(|| Error::UnknownLauncherProfile(profile_id.clone()))?;
-            let world_version = statuses.get(&world_name).ok_or_else(|| Error::UnknownWorldName(profile_id, world_name))?
-                .version.as_ref().ok_or(Error::ModdedVersion)?;
-            if launcher_profile.last_version_id != *world_version {
-                launcher_profile.last_version_id = world_version.clone();
-                modified = true;
-            }
+    Ok(())
+}
+
+/// Prints the session history, optionally filtered using `--player` (a Wurstmineberg ID, Discord snowflake, or name) and/or `--world`.
+#[bitbar::command(varargs)]
+fn history(args: Vec<String>) -> Result<(), Error> {
+    let mut player = None;
+    let mut world = None;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match &*arg {
+            "--player" => player = Some(args.next().ok_or(Error::MissingArgumentValue(arg))?),
+            "--world" => world = Some(args.next().ok_or(Error::MissingArgumentValue(arg))?),
+            _ => return Err(Error::UnknownArgument(arg)),
         }
-        if modified { launcher_data.save()? }
     }
-    if statuses.values().all(|status| status.list.is_empty())
-    && !if statuses[MAIN_WORLD].running { config.show_if_empty } else { config.show_if_offline } {
-        return Ok(Menu::default())
+    // names are looked up in the last retrieved people lists, so this works offline
+    let mut people = HashMap::<String, People>::default();
+    for entry in history::load()? {
+        if world.as_ref().is_some_and(|world| *world != entry.event.world) { continue }
+        let server_people = match people.entry(entry.event.server.clone()) {
+            hash_map::Entry::Occupied(occupied) => occupied.into_mut(),
+            hash_map::Entry::Vacant(vacant) => vacant.insert(Snapshot::<People>::load(&entry.event.server, "people")?.map(|snapshot| snapshot.value).unwrap_or_default()),
+        };
+        if let Some(ref player) = player {
+            let name = server_people.get(&entry.event.uid).and_then(|person| person.name.as_ref());
+            if entry.event.uid.to_string() != *player && name.is_none_or(|name| !name.eq_ignore_ascii_case(player)) { continue }
+        }
+        println!("{} {}: {}", entry.time.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S"), entry.event.server, entry.event.body(server_people));
     }
-    let people = People::load(&client).await?;
-    let mut cache = Cache::load()?;
-    let mut menu = vec![{
-        let total = statuses.values().map(|status| status.list.len()).sum::<usize>();
-        let head = ContentItem::new(if total > 0 {
-            Cow::Owned(total.to_string())
-        } else if !statuses[MAIN_WORLD].running {
-            Cow::Borrowed("!")
-        } else {
-            Cow::Borrowed("")
-        }).template_image(wurstpick(config.zoom))?;
-        if let Some(fav_color) = (config.single_color && total == 1).then(|| ())
-            .and_then(|()| people.get(statuses.values().flat_map(|status| &status.list).exactly_one().expect("total == 1 but not exactly 1 player online")))
-            .and_then(|person| person.fav_color)
-        { head.color(fav_color)? } else { head }.into()
-    }];
-    for (world_name, status) in statuses {
-        if (world_name == MAIN_WORLD && !status.running) || !status.list.is_empty() {
-            menu.push(MenuItem::Sep);
-            menu.push(MenuItem::new(world_name));
-            menu.push(if status.running {
-                if let Some(version) = status.version {
-                    let version_item = ContentItem::new(format!("Version: {version}"));
-                    match config.version_link {
-                        VersionLink::Enabled => version_item.href(format!("https://minecraft.wiki/w/Java_Edition_{version}"))?,
-                        VersionLink::Alternate => version_item.alt(ContentItem::new(format!("Version: {version}")).color("blue")?.href(format!("https://minecraft.wiki/w/Java_Edition_{version}"))?),
-                        VersionLink::Disabled => version_item,
-                    }.into()
-                } else {
-                    MenuItem::new("Modded Server, Unknown Version")
-                }
+    Ok(())
+}
+
+fn http_client() -> Result<reqwest::Client, Error> {
+    Ok(reqwest::Client::builder()
+        .user_agent(concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION")))
+        .timeout(Duration::from_secs(30))
+        .use_rustls_tls()
+        .build()?)
+}
+
+/// Validates the config file more strictly than when loading it normally, and prints a report.
+#[bitbar::command]
+async fn check_config() -> Result<(), Error> {
+    let problems = check::check_config(&http_client()?).await?;
+    if problems > 0 { return Err(Error::ConfigProblems(problems)) }
+    Ok(())
+}
+
+/// Prints a JSON Schema for the config file, for editor autocompletion and validation.
+#[bitbar::command]
+fn print_config_schema() -> Result<(), Error> {
+    println!("{}", serde_json::to_string_pretty(&schemars::schema_for!(Config))?);
+    Ok(())
+}
+
+/// Prints the state as JSON for a Waybar custom module.
+#[bitbar::command]
+async fn waybar() -> Result<(), Error> {
+    let config = Config::load()?;
+    let http_client = http_client()?;
+    println!("{}", output::waybar(&State::load(&http_client, &config).await?, &config));
+    Ok(())
+}
+
+/// Prints the fully processed state as JSON, for scripting and debugging.
+#[bitbar::command]
+async fn json() -> Result<(), Error> {
+    let config = Config::load()?;
+    let http_client = http_client()?;
+    println!("{:#}", output::json(&State::load_read_only(&http_client, &config).await?, &config));
+    Ok(())
+}
+
+/// Syncs launcher profiles according to `versionMatch` and prints the outcome for each entry. With `--dry-run`, nothing is modified.
+#[bitbar::command(varargs)]
+async fn version_match(args: Vec<String>) -> Result<(), Error> {
+    let dry_run = match &*args {
+        [] => false,
+        [arg] if arg == "--dry-run" => true,
+        [arg, ..] => return Err(Error::UnknownArgument(arg.clone())),
+    };
+    let config = Config::load()?;
+    let http_client = http_client()?;
+    let report = if dry_run { State::load_read_only(&http_client, &config).await? } else { State::load(&http_client, &config).await? }.launcher_sync;
+    for mapping in report.mappings {
+        match mapping.result {
+            Ok(change) => if change.current == change.target {
+                println!("{} ({}): {}, up to date", mapping.profile_id, mapping.world, change.target);
+            } else if change.updated {
+                println!("{} ({}): updated from {} to {}", mapping.profile_id, mapping.world, change.current, change.target);
+            } else if dry_run {
+                println!("{} ({}): would update from {} to {}", mapping.profile_id, mapping.world, change.current, change.target);
             } else {
-                MenuItem::new("Offline") //TODO add link to Discord channel?
-            });
-            for uid in status.list {
-                let person = people.get(&uid).cloned().unwrap_or_default();
-                let mut item = ContentItem::new(person.name.map_or_else(|| uid.to_string(), |name| name.to_string()))
-                    .href(format!("https://wurstmineberg.de/people/{uid}"))?
-                    .image(cache.get_img(&client, uid.clone(), config.zoom).await?)?;
-                if let Some(fav_color) = person.fav_color {
-                    item = item.color(fav_color)?;
-                }
-                if let Some(discord) = person.discord {
-                    item = item.alt(
-                        ContentItem::new(format!("@{}", discord.name()))
-                            .color("blue")?
-                            .href(discord.url())?
-                            .image(cache.get_img(&client, uid.clone(), config.zoom).await?)?
-                    );
-                }
-                menu.push(item.into());
-            }
+                println!("{} ({}): failed to update from {} to {}", mapping.profile_id, mapping.world, change.current, change.target);
+            },
+            Err(e) => println!("{} ({}): error: {e}", mapping.profile_id, mapping.world),
         }
     }
-    menu.push(MenuItem::Sep);
-    menu.push(ContentItem::new("Start Minecraft")
-        .command(("/usr/bin/open", "-a", "Minecraft"))?
-        .into());
-    if !config.defer_specs.is_empty() {
-        menu.push(MenuItem::Sep);
-        for spec in config.defer_specs {
-            menu.push(ContentItem::new(format!("Defer Until {}", spec.iter().format(" ")))
-                .command(
-                    Command::try_from(
-                        vec![format!("{}", current_exe.display()), format!("defer")]
-                            .into_iter()
-                            .chain(spec)
-                            .collect::<Vec<_>>()
-                    ).map_err(|v| Error::CommandLength(v.len()))?
-                )?
-                .refresh()
-                .into());
+    for e in report.save_errors {
+        println!("error: {e}");
+    }
+    Ok(())
+}
+
+/// Runs as an i3bar/swaybar status command.
+#[bitbar::command]
+async fn i3bar() -> Result<(), Error> {
+    let config = Config::load()?;
+    let http_client = http_client()?;
+    output::i3bar(&http_client, &config).await
+}
+
+/// Prints the state as a line of text for a Polybar script module.
+#[bitbar::command]
+async fn polybar() -> Result<(), Error> {
+    let config = Config::load()?;
+    let http_client = http_client()?;
+    println!("{}", output::polybar(&State::load(&http_client, &config).await?, &config));
+    Ok(())
+}
+
+#[bitbar::main(
+    error_template_image = "../assets/wurstpick-2x.png", // tagged as 144 DPI, so macOS displays it at the same size on low-DPI screens
+    commands(check_config, defer, defer_status, defer_world, history, i3bar, json, polybar, print_config_schema, undefer, undefer_world, version_match, waybar),
+)]
+async fn main() -> Result<Menu, Error> {
+    let config = Config::load()?;
+    let http_client = http_client()?;
+    match config.output {
+        Output::BitBar => output::bitbar(State::load(&http_client, &config).await?, &config).await,
+        // the plugin was started without a subcommand but configured to use another output mode, print the output directly and leave the BitBar menu empty
+        Output::Waybar => {
+            println!("{}", output::waybar(&State::load(&http_client, &config).await?, &config));
+            Ok(Menu::default())
+        }
+        Output::I3bar => {
+            output::i3bar(&http_client, &config).await?;
+            Ok(Menu::default())
+        }
+        Output::Polybar => {
+            println!("{}", output::polybar(&State::load(&http_client, &config).await?, &config));
+            Ok(Menu::default())
         }
     }
-    cache.save()?;
-    Ok(Menu(menu))
 }
@@ -1,5 +1,15 @@
 use {
-    std::fmt,
+    std::{
+        borrow::Cow,
+        collections::HashMap,
+        fmt,
+    },
+    schemars::{
+        JsonSchema,
+        Schema,
+        SchemaGenerator,
+        json_schema,
+    },
     serde::{
         Deserialize,
         Serialize,
@@ -8,11 +18,97 @@ use {
     url::Url,
 };
 
-#[derive(Debug, Deserialize, Clone, Copy)]
-pub(crate) struct Color {
-    red: u8,
-    green: u8,
-    blue: u8,
+/// The status of a Minecraft world, as returned by the `server/worlds.json` endpoint.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Status {
+    #[serde(default)]
+    pub list: Vec<Uid>,
+    /// The number of players online who aren't included in `list`.
+    /// Only nonzero if the status was retrieved using [`crate::ping`], since servers only report a sample of the players online and may hide some of them.
+    #[serde(default)]
+    pub unlisted: usize,
+    pub running: bool,
+    pub version: Option<String>,
+    /// The message of the day. Only available when the status was retrieved using [`crate::ping`].
+    #[serde(default)]
+    pub motd: Option<String>,
+    /// The mod loader of a modded world, if published by the API.
+    #[serde(default)]
+    pub modloader: Option<ModLoaderInfo>,
+    /// The modpack a modded world is running, if published by the API.
+    #[serde(default)]
+    pub modpack: Option<ModpackInfo>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ModLoader {
+    Fabric,
+    Forge,
+    NeoForge,
+    Quilt,
+}
+
+impl fmt::Display for ModLoader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Fabric => write!(f, "Fabric"),
+            Self::Forge => write!(f, "Forge"),
+            Self::NeoForge => write!(f, "NeoForge"),
+            Self::Quilt => write!(f, "Quilt"),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ModLoaderInfo {
+    pub name: ModLoader,
+    pub version: String,
+    /// The Minecraft version the mod loader runs on. Modded worlds usually don't report this as [`Status::version`].
+    #[serde(default)]
+    pub minecraft_version: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ModpackInfo {
+    pub name: String,
+    #[serde(default)]
+    pub version: Option<String>,
+    #[serde(default)]
+    pub url: Option<Url>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize, Clone)]
+pub struct People {
+    pub people: HashMap<Uid, Person>,
+}
+
+impl People {
+    pub fn get(&self, uid: impl Into<Uid>) -> Option<&Person> {
+        self.people.get(&uid.into())
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct AvatarInfo {
+    pub url: Url,
+    #[serde(default)]
+    pub fallbacks: Vec<AvatarInfo>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy)]
+pub struct Color {
+    pub red: u8,
+    pub green: u8,
+    pub blue: u8,
+}
+
+impl Color {
+    /// The color in `#rrggbb` notation.
+    pub fn hex(&self) -> String {
+        format!("#{:02x}{:02x}{:02x}", self.red, self.green, self.blue)
+    }
 }
 
 impl From<Color> for bitbar::attr::Color {
@@ -26,36 +122,56 @@ impl From<Color> for bitbar::attr::Color {
     }
 }
 
-#[derive(Debug, Deserialize, Clone)]
-pub(crate) struct DiscordData {
-    nick: Option<String>,
-    snowflake: UserId,
-    username: String,
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct DiscordData {
+    pub nick: Option<String>,
+    pub snowflake: UserId,
+    pub username: String,
 }
 
 impl DiscordData {
-    pub(crate) fn name(&self) -> &str {
+    pub fn name(&self) -> &str {
         self.nick.as_ref().unwrap_or(&self.username)
     }
 
-    pub(crate) fn url(&self) -> Url {
+    pub fn url(&self) -> Url {
         format!("https://discordapp.com/users/{}/", self.snowflake).parse().expect("failed to parse Discord user URL")
     }
 }
 
-#[derive(Debug, Default, Deserialize, Clone)]
+#[derive(Debug, Default, Deserialize, Serialize, Clone)]
 #[serde(rename_all = "camelCase")]
-pub(crate) struct Person {
-    pub(crate) discord: Option<DiscordData>,
-    pub(crate) fav_color: Option<Color>,
-    pub(crate) name: Option<String>,
+pub struct Person {
+    pub discord: Option<DiscordData>,
+    pub fav_color: Option<Color>,
+    pub minecraft: Option<MinecraftData>,
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize, Clone)]
+pub struct MinecraftData {
+    #[serde(default)]
+    pub nicks: Vec<String>,
+    pub uuid: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[serde(untagged)]
-pub(crate) enum Uid {
+pub enum Uid {
     Snowflake(UserId),
     WmbId(String),
+    /// A player who was seen on a server using [`crate::ping`] but isn't a Wurstmineberg person, identified by their Minecraft username.
+    /// Such players have no profile page or avatar.
+    Minecraft {
+        minecraft: String,
+    },
+}
+
+impl Uid {
+    /// Whether this identifies a Wurstmineberg person, as opposed to [`Uid::Minecraft`]. Only people have profile pages and avatars.
+    pub fn is_person(&self) -> bool {
+        !matches!(self, Uid::Minecraft { .. })
+    }
 }
 
 impl<T: Clone + Into<Uid>> From<&T> for Uid {
@@ -64,11 +180,39 @@ impl<T: Clone + Into<Uid>> From<&T> for Uid {
     }
 }
 
+impl JsonSchema for Uid {
+    fn schema_name() -> Cow<'static, str> {
+        Cow::Borrowed("Uid")
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        json_schema!({
+            "description": "A Wurstmineberg ID or Discord snowflake, or an object with a Minecraft username as its `minecraft` entry for players who aren't Wurstmineberg people.",
+            "anyOf": [
+                {
+                    "type": ["string", "integer"],
+                },
+                {
+                    "type": "object",
+                    "properties": {
+                        "minecraft": {
+                            "type": "string",
+                        },
+                    },
+                    "required": ["minecraft"],
+                    "additionalProperties": false,
+                },
+            ],
+        })
+    }
+}
+
 impl fmt::Display for Uid {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Uid::Snowflake(snowflake) => snowflake.fmt(f),
             Uid::WmbId(wmb_id) => wmb_id.fmt(f),
+            Uid::Minecraft { minecraft } => minecraft.fmt(f),
         }
     }
 }
@@ -0,0 +1,97 @@
+use {
+    std::{
+        collections::BTreeMap,
+        env,
+        path::Path,
+        process::Command,
+    },
+    serde::{
+        Deserialize,
+        Serialize,
+    },
+    url::Url,
+    bitbar_wurstmineberg_status::{
+        People,
+        Status,
+        Uid,
+    },
+    crate::{
+        Error,
+        files::NotifyConfig,
+    },
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum EventKind {
+    Join,
+    Leave,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub(crate) struct Event {
+    pub(crate) kind: EventKind,
+    pub(crate) server: String,
+    pub(crate) world: String,
+    pub(crate) uid: Uid,
+}
+
+impl Event {
+    pub(crate) fn body(&self, people: &People) -> String {
+        let name = people.get(&self.uid).and_then(|person| person.name.clone()).unwrap_or_else(|| self.uid.to_string());
+        match self.kind {
+            EventKind::Join => format!("{name} joined {}", self.world),
+            EventKind::Leave => format!("{name} left {}", self.world),
+        }
+    }
+}
+
+/// Compares the player lists from the previous run with the current ones.
+///
+/// Worlds which were not known during the previous run produce no events, so the first run after installing the plugin doesn't report everyone as having joined.
+pub(crate) fn diff(server: &str, previous: &BTreeMap<String, Vec<Uid>>, statuses: &BTreeMap<String, Status>) -> Vec<Event> {
+    let mut events = Vec::default();
+    for (world, status) in statuses {
+        let Some(previous) = previous.get(world) else { continue };
+        for uid in &status.list {
+            if !previous.contains(uid) {
+                events.push(Event { kind: EventKind::Join, server: server.to_owned(), world: world.clone(), uid: uid.clone() });
+            }
+        }
+        for uid in previous {
+            if !status.list.contains(uid) {
+                events.push(Event { kind: EventKind::Leave, server: server.to_owned(), world: world.clone(), uid: uid.clone() });
+            }
+        }
+    }
+    events
+}
+
+impl NotifyConfig {
+    pub(crate) fn matches(&self, event: &Event) -> bool {
+        self.worlds.as_ref().is_none_or(|worlds| worlds.contains(&event.world))
+        && self.players.as_ref().is_none_or(|players| players.contains(&event.uid))
+        && match event.kind {
+            EventKind::Join => self.join,
+            EventKind::Leave => self.leave,
+        }
+    }
+
+    /// Delivers a notification using the configured notifier command if any, or SwiftBar's URL scheme otherwise.
+    ///
+    /// Does nothing if there is no notifier command and the plugin isn't running in SwiftBar.
+    pub(crate) fn send(&self, event: &Event, people: &People) -> Result<(), Error> {
+        let title = &*event.server;
+        let body = event.body(people);
+        if let Some((cmd, args)) = self.command.split_first() {
+            let status = Command::new(cmd).args(args).arg(title).arg(&body).status()?;
+            if !status.success() { return Err(Error::CommandExit("notifier", status)) }
+        } else if let Some(plugin_path) = env::var_os("SWIFTBAR_PLUGIN_PATH") {
+            let plugin_id = Path::new(&plugin_path).file_name().and_then(|file_name| file_name.to_str()).ok_or(Error::EnvVarNotUnicode(format!("SWIFTBAR_PLUGIN_PATH")))?;
+            let url = Url::parse_with_params("swiftbar://notify", &[("plugin", plugin_id), ("title", title), ("body", &*body)])?;
+            let status = Command::new("/usr/bin/open").arg("-g").arg(url.as_str()).status()?;
+            if !status.success() { return Err(Error::CommandExit("open", status)) }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,502 @@
+use {
+    std::{
+        borrow::Cow,
+        collections::HashSet,
+        env,
+        time::Duration,
+    },
+    bitbar::{
+        ContentItem,
+        Menu,
+        MenuItem,
+        attr::Command,
+    },
+    chrono::{
+        TimeDelta,
+        prelude::*,
+    },
+    itertools::Itertools as _,
+    schemars::JsonSchema,
+    serde::Deserialize,
+    serde_json::{
+        Value as Json,
+        json,
+    },
+    tokio::time::sleep,
+    crate::{
+        Error,
+        files::{
+            Cache,
+            Config,
+            VersionLink,
+        },
+        state::{
+            RecentPlayer,
+            Server,
+            State,
+        },
+        wurstpick,
+    },
+};
+
+/// How often the state is refreshed in i3bar mode, which unlike the other output modes is a long-running process.
+const I3BAR_INTERVAL: Duration = Duration::from_secs(45);
+
+/// The format in which the plugin's state is output.
+#[derive(Debug, Default, Clone, Copy, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Output {
+    /// A BitBar menu, for SwiftBar or xbar.
+    #[default]
+    BitBar,
+    /// JSON for a Waybar custom module with `"return-type": "json"`.
+    Waybar,
+    /// The i3bar protocol, for i3bar or swaybar. Keeps running and prints a new status line periodically.
+    I3bar,
+    /// A single line of text with Polybar formatting tags, for a Polybar script module.
+    Polybar,
+}
+
+/// Escapes text for use in Pango markup, which Waybar uses for its text and tooltips.
+fn pango_escape(text: &str) -> Cow<'_, str> {
+    if text.contains(['&', '<', '>']) {
+        Cow::Owned(text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;"))
+    } else {
+        Cow::Borrowed(text)
+    }
+}
+
+/// Escapes text for use in Polybar, where `%` starts a formatting tag.
+fn polybar_escape(text: &str) -> Cow<'_, str> {
+    if text.contains('%') {
+        Cow::Owned(text.replace('%', "%%"))
+    } else {
+        Cow::Borrowed(text)
+    }
+}
+
+/// Formats how long ago something happened, e.g. “5 minutes ago”.
+fn format_ago(delta: TimeDelta) -> String {
+    match delta.num_minutes() {
+        ..1 => format!("just now"),
+        1 => format!("1 minute ago"),
+        minutes @ 2..60 => format!("{minutes} minutes ago"),
+        60..120 => format!("1 hour ago"),
+        minutes @ 120..1440 => format!("{} hours ago", minutes / 60),
+        1440..2880 => format!("1 day ago"),
+        minutes => format!("{} days ago", minutes / 1440),
+    }
+}
+
+fn head_text(state: &State, config: &Config) -> Cow<'static, str> {
+    let total = state.total();
+    if total > 0 {
+        Cow::Owned(if config.count_watched_players {
+            format!("{}★/{total}", state.watched_total())
+        } else {
+            total.to_string()
+        })
+    } else if !state.main_world_running() {
+        Cow::Borrowed("!")
+    } else {
+        Cow::Borrowed("")
+    }
+}
+
+/// A menu item for a player from the session history, e.g. “Alice — wurstmineberg, 5 minutes ago”.
+fn recent_item(recent: RecentPlayer, servers: &[Server], now: DateTime<Utc>) -> Result<MenuItem, Error> {
+    let mut item = ContentItem::new(if servers.len() > 1 {
+        format!("{} — {} on {}, {}", recent.player.label(), recent.world, recent.server, format_ago(now - recent.left))
+    } else {
+        format!("{} — {}, {}", recent.player.label(), recent.world, format_ago(now - recent.left))
+    });
+    if let Some(client) = servers.iter().find(|server| server.name == recent.server).and_then(|server| server.client.as_ref())
+        && let Some(url) = client.person_url(&recent.player.uid)?
+    {
+        item = item.href(url)?;
+    }
+    if let Some(fav_color) = recent.player.fav_color {
+        item = item.color(fav_color)?;
+    }
+    Ok(item.into())
+}
+
+pub(crate) async fn bitbar(state: State, config: &Config) -> Result<Menu, Error> {
+    if state.hidden.is_some() { return Ok(Menu::default()) }
+    let current_exe = env::current_exe()?;
+    let mut cache = Cache::load()?;
+    let mut menu = vec![if state.deferred.is_some() {
+        // showDeferred is enabled, so show the deferral instead of hiding the plugin, with just the icon in the menu bar
+        ContentItem::new("").template_image(wurstpick(config.zoom()))?.into()
+    } else {
+        let head = ContentItem::new(head_text(&state, config)).template_image(wurstpick(config.zoom()))?;
+        if let Some(fav_color) = state.single_color(config) { head.color(fav_color)? } else { head }.into()
+    }];
+    if let Some(deferred) = state.deferred {
+        menu.push(MenuItem::Sep);
+        menu.push(MenuItem::new(format!("Deferred Until {}", deferred.with_timezone(&Local).format("%Y-%m-%d %H:%M"))));
+        menu.push(ContentItem::new("Resume Now")
+            .command(Command::try_from(vec![format!("{}", current_exe.display()), format!("undefer")]).map_err(|v| Error::CommandLength(v.len()))?)?
+            .refresh()
+            .into());
+    }
+    if let Some(stale_since) = state.stale_since() {
+        menu.push(MenuItem::Sep);
+        menu.push(ContentItem::new(format!("Stale since {}", stale_since.with_timezone(&Local).format("%H:%M")))
+            .color("red")?
+            .sub(state.stale.into_iter().flat_map(|(_, e)| Menu::from(e).0))
+            .into());
+    }
+    if !config.warnings.is_empty() {
+        menu.push(MenuItem::Sep);
+        for warning in &config.warnings {
+            menu.push(ContentItem::new(format!("Config: {warning}"))
+                .color("orange")?
+                .into());
+        }
+    }
+    // avatars of people who are no longer listed on any server will never be needed again
+    let known_people = state.servers.iter().flat_map(|server| server.people.people.keys().map(|uid| (&*server.name, uid))).collect::<HashSet<_>>();
+    cache.retain(|server, uid| known_people.contains(&(server, uid)))?;
+    let (mut avatars, avatar_errors) = cache.get_imgs(
+        state.servers.iter()
+            .filter_map(|server| Some((server.client.as_ref()?, server)))
+            .flat_map(|(client, server)| server.listed_worlds().flat_map(|(_, world)| &world.players).filter(|player| player.uid.is_person()).map(move |player| (&*server.name, client, player.uid.clone()))),
+        config.zoom(),
+        TimeDelta::seconds(config.avatar_max_age.into()),
+        config.avatar_concurrency,
+    ).await?;
+    if !avatar_errors.is_empty() {
+        // players without an avatar are still listed, so this is only a warning
+        menu.push(MenuItem::Sep);
+        menu.push(ContentItem::new(if avatar_errors.len() == 1 { format!("1 avatar could not be loaded") } else { format!("{} avatars could not be loaded", avatar_errors.len()) })
+            .color("orange")?
+            .sub(avatar_errors.into_iter().flat_map(|e| Menu::from(e).0))
+            .into());
+    }
+    let multiple_servers = state.servers.len() > 1;
+    let now = Utc::now();
+    let recent_menu = state.recently_online.into_iter().map(|recent| recent_item(recent, &state.servers, now)).collect::<Result<Vec<_>, _>>()?;
+    let last_seen_menu = state.watched_last_seen.into_iter().map(|recent| recent_item(recent, &state.servers, now)).collect::<Result<Vec<_>, _>>()?;
+    for Server { name, main_world, client, worlds, .. } in state.servers {
+        let avatars = avatars.remove(&name).unwrap_or_default();
+        let mut header = multiple_servers.then(|| name.clone());
+        for (world_name, world) in worlds {
+            if (world_name == main_world && !world.running) || world.total() > 0 {
+                if let Some(server_name) = header.take() {
+                    menu.push(MenuItem::Sep);
+                    menu.push(MenuItem::new(server_name));
+                }
+                menu.push(MenuItem::Sep);
+                menu.push(MenuItem::new(&world_name));
+                menu.push(if world.running {
+                    if let Some(version) = world.version {
+                        let version_item = ContentItem::new(format!("Version: {version}"));
+                        match config.version_link {
+                            VersionLink::Enabled => version_item.href(format!("https://minecraft.wiki/w/Java_Edition_{version}"))?,
+                            VersionLink::Alternate => version_item.alt(ContentItem::new(format!("Version: {version}")).color("blue")?.href(format!("https://minecraft.wiki/w/Java_Edition_{version}"))?),
+                            VersionLink::Disabled => version_item,
+                        }.into()
+                    } else if let Some(modloader) = world.modloader {
+                        if let Some(minecraft_version) = modloader.minecraft_version {
+                            MenuItem::new(format!("Modded Server: {} {} for {minecraft_version}", modloader.name, modloader.version))
+                        } else {
+                            MenuItem::new(format!("Modded Server: {} {}", modloader.name, modloader.version))
+                        }
+                    } else {
+                        MenuItem::new("Modded Server, Unknown Version")
+                    }
+                } else {
+                    MenuItem::new("Offline") //TODO add link to Discord channel?
+                });
+                if let Some(modpack) = world.modpack {
+                    let modpack_item = ContentItem::new(if let Some(version) = modpack.version {
+                        format!("Modpack: {} {version}", modpack.name)
+                    } else {
+                        format!("Modpack: {}", modpack.name)
+                    });
+                    menu.push(if let Some(url) = modpack.url { modpack_item.href(url)? } else { modpack_item }.into());
+                }
+                if !config.defer_specs.is_empty() {
+                    let mut mute_menu = Vec::with_capacity(config.defer_specs.len());
+                    for spec in &config.defer_specs {
+                        mute_menu.push(ContentItem::new(format!("Until {}", spec.iter().format(" ")))
+                            // the timespec is passed as a single argument to stay within the limit on command parameters
+                            .command(Command::try_from(vec![format!("{}", current_exe.display()), format!("defer_world"), name.clone(), world_name.clone(), spec.join(" ")]).map_err(|v| Error::CommandLength(v.len()))?)?
+                            .refresh()
+                            .into());
+                    }
+                    menu.push(ContentItem::new("Mute World").sub(mute_menu).into());
+                }
+                for player in world.players {
+                    let mut item = ContentItem::new(player.label());
+                    if let Some(ref client) = client
+                        && let Some(url) = client.person_url(&player.uid)?
+                    {
+                        item = item.href(url)?;
+                    }
+                    let avatar = avatars.get(&player.uid);
+                    if let Some(avatar) = avatar {
+                        item = item.image(avatar)?;
+                    }
+                    if let Some(fav_color) = player.fav_color {
+                        item = item.color(fav_color)?;
+                    }
+                    if let Some(discord) = player.discord {
+                        let mut alt = ContentItem::new(format!("@{}", discord.name()))
+                            .color("blue")?
+                            .href(discord.url())?;
+                        if let Some(avatar) = avatar {
+                            alt = alt.image(avatar)?;
+                        }
+                        item = item.alt(alt);
+                    }
+                    menu.push(item.into());
+                }
+                if world.unlisted > 0 {
+                    menu.push(MenuItem::new(format!("and {} more", world.unlisted)));
+                }
+            }
+        }
+    }
+    if !recent_menu.is_empty() || !last_seen_menu.is_empty() {
+        menu.push(MenuItem::Sep);
+        if !recent_menu.is_empty() {
+            menu.push(ContentItem::new("Recently Online").sub(recent_menu).into());
+        }
+        if !last_seen_menu.is_empty() {
+            menu.push(ContentItem::new("Watched Players Last Seen").sub(last_seen_menu).into());
+        }
+    }
+    if !state.muted.is_empty() {
+        menu.push(MenuItem::Sep);
+        for (server_name, worlds) in state.muted {
+            for (world_name, deferred) in worlds {
+                menu.push(ContentItem::new(if multiple_servers {
+                    format!("{world_name} on {server_name} Muted Until {}", deferred.with_timezone(&Local).format("%Y-%m-%d %H:%M"))
+                } else {
+                    format!("{world_name} Muted Until {}", deferred.with_timezone(&Local).format("%Y-%m-%d %H:%M"))
+                })
+                    .sub([ContentItem::new("Unmute Now")
+                        .command(Command::try_from(vec![format!("{}", current_exe.display()), format!("undefer_world"), server_name.clone(), world_name]).map_err(|v| Error::CommandLength(v.len()))?)?
+                        .refresh()
+                        .into()])
+                    .into());
+            }
+        }
+    }
+    if !state.launcher_sync.mappings.is_empty() || !state.launcher_sync.save_errors.is_empty() {
+        menu.push(MenuItem::Sep);
+        menu.push(MenuItem::new("Launcher Profiles"));
+        for mapping in state.launcher_sync.mappings {
+            menu.push(match mapping.result {
+                Ok(change) => MenuItem::new(if change.current == change.target {
+                    format!("{} ({}): {}", mapping.profile_id, mapping.world, change.target)
+                } else if change.updated {
+                    format!("{} ({}): {} → {}, updated", mapping.profile_id, mapping.world, change.current, change.target)
+                } else {
+                    format!("{} ({}): {} → {}, not updated", mapping.profile_id, mapping.world, change.current, change.target)
+                }),
+                Err(e) => ContentItem::new(format!("{} ({}): {e}", mapping.profile_id, mapping.world))
+                    .color("orange")?
+                    .sub(Menu::from(e).0)
+                    .into(),
+            });
+        }
+        for e in state.launcher_sync.save_errors {
+            menu.push(ContentItem::new(e.to_string())
+                .color("orange")?
+                .sub(Menu::from(e).0)
+                .into());
+        }
+    }
+    menu.push(MenuItem::Sep);
+    menu.push(ContentItem::new("Start Minecraft")
+        .command(("/usr/bin/open", "-a", "Minecraft"))?
+        .into());
+    if !config.defer_specs.is_empty() {
+        menu.push(MenuItem::Sep);
+        for spec in &config.defer_specs {
+            menu.push(ContentItem::new(format!("Defer Until {}", spec.iter().format(" ")))
+                .command(
+                    Command::try_from(
+                        vec![format!("{}", current_exe.display()), format!("defer")]
+                            .into_iter()
+                            .chain(spec.iter().cloned())
+                            .collect::<Vec<_>>()
+                    ).map_err(|v| Error::CommandLength(v.len()))?
+                )?
+                .refresh()
+                .into());
+        }
+    }
+    cache.save()?;
+    Ok(Menu(menu))
+}
+
+pub(crate) fn waybar(state: &State, config: &Config) -> Json {
+    if state.is_hidden() { return json!({ "text": "", "class": "hidden" }) }
+    let mut tooltip = Vec::default();
+    let multiple_servers = state.servers.len() > 1;
+    for server in &state.servers {
+        let mut header = multiple_servers.then_some(&server.name);
+        for (world_name, world) in server.listed_worlds() {
+            if let Some(server_name) = header.take() {
+                tooltip.push(format!("<big>{}</big>", pango_escape(server_name)));
+            }
+            tooltip.push(format!("<b>{}</b>: {}", pango_escape(world_name), if world.running {
+                world.version.as_deref().map_or(Cow::Borrowed("modded"), pango_escape)
+            } else {
+                Cow::Borrowed("offline")
+            }));
+            for player in &world.players {
+                tooltip.push(if let Some(fav_color) = player.fav_color {
+                    format!("  <span color=\"{}\">{}</span>", fav_color.hex(), pango_escape(&player.label()))
+                } else {
+                    format!("  {}", pango_escape(&player.label()))
+                });
+            }
+            if world.unlisted > 0 {
+                tooltip.push(format!("  and {} more", world.unlisted));
+            }
+        }
+    }
+    if let Some(stale_since) = state.stale_since() {
+        tooltip.push(format!("<i>stale since {}</i>", stale_since.with_timezone(&Local).format("%H:%M")));
+    }
+    let text = head_text(state, config);
+    json!({
+        "text": if let Some(fav_color) = state.single_color(config).map(|fav_color| fav_color.hex()) {
+            format!("<span color=\"{fav_color}\">{text}</span>")
+        } else {
+            text.into_owned()
+        },
+        "tooltip": tooltip.join("\n"),
+        "class": if state.stale_since().is_some() {
+            "stale"
+        } else if state.total() > 0 {
+            "online"
+        } else if !state.main_world_running() {
+            "offline"
+        } else {
+            "empty"
+        },
+    })
+}
+
+fn i3bar_block(state: &State, config: &Config) -> Json {
+    let mut block = json!({
+        "name": "wurstmineberg",
+        "full_text": if state.is_hidden() {
+            String::default()
+        } else {
+            state.players()
+                .map(|player| player.label())
+                .chain((state.unlisted() > 0).then(|| Cow::Owned(format!("{} more", state.unlisted()))))
+                .join(", ")
+        },
+        "short_text": if state.is_hidden() { Cow::Borrowed("") } else { head_text(state, config) },
+    });
+    if let Some(fav_color) = state.single_color(config).map(|fav_color| fav_color.hex()) {
+        block["color"] = json!(fav_color);
+    } else if !state.is_hidden() && !state.main_world_running() {
+        block["urgent"] = json!(true);
+    }
+    block
+}
+
+/// The fully processed state in a stable format for scripting and debugging. See the readme for the schema.
+pub(crate) fn json(state: &State, config: &Config) -> Json {
+    json!({
+        "deferred": state.deferred,
+        "hidden": state.hidden,
+        "mutedWorlds": state.muted,
+        "staleSince": state.stale_since(),
+        "errors": state.stale.iter().map(|(_, e)| e.to_string()).collect_vec(),
+        "total": state.total(),
+        "watchedTotal": state.watched_total(),
+        "singleColor": state.single_color(config).map(|fav_color| fav_color.hex()),
+        "servers": state.servers,
+        "recentlyOnline": state.recently_online,
+        "watchedLastSeen": state.watched_last_seen,
+        "versionMatch": state.launcher_sync.mappings.iter().map(|mapping| match mapping.result {
+            Ok(ref change) => json!({
+                "profile": mapping.profile_id,
+                "world": mapping.world,
+                "current": change.current,
+                "target": change.target,
+                "updated": change.updated,
+            }),
+            Err(ref e) => json!({
+                "profile": mapping.profile_id,
+                "world": mapping.world,
+                "error": e.to_string(),
+            }),
+        }).collect_vec(),
+        "launcherErrors": state.launcher_sync.save_errors.iter().map(|e| e.to_string()).collect_vec(),
+    })
+}
+
+/// Prints the i3bar protocol header, then a status line every [`I3BAR_INTERVAL`].
+///
+/// Errors are displayed in the status line instead of ending the process, since i3bar doesn't restart it.
+pub(crate) async fn i3bar(http_client: &reqwest::Client, config: &Config) -> Result<(), Error> {
+    println!("{}", json!({ "version": 1 }));
+    println!("[");
+    loop {
+        let block = match State::load(http_client, config).await {
+            Ok(state) => i3bar_block(&state, config),
+            Err(e) => json!({ "name": "wurstmineberg", "full_text": format!("Wurstmineberg: {e}"), "urgent": true }),
+        };
+        println!("{},", json!([block]));
+        sleep(I3BAR_INTERVAL).await;
+    }
+}
+
+pub(crate) fn polybar(state: &State, config: &Config) -> String {
+    if state.is_hidden() { return String::default() }
+    let mut line = head_text(state, config).into_owned();
+    if let Some(fav_color) = state.single_color(config).map(|fav_color| fav_color.hex()) {
+        line = format!("%{{F{fav_color}}}{line}%{{F-}}");
+    }
+    let players = state.players()
+        .map(|player| {
+            let label = polybar_escape(&player.label()).into_owned();
+            if let Some(fav_color) = player.fav_color {
+                format!("%{{F{}}}{label}%{{F-}}", fav_color.hex())
+            } else {
+                label
+            }
+        })
+        .chain((state.unlisted() > 0).then(|| format!("{} more", state.unlisted())))
+        .join(", ");
+    if !players.is_empty() {
+        line.push_str(": ");
+        line.push_str(&players);
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escape() {
+        assert_eq!(pango_escape("a<b> & c"), "a&lt;b&gt; &amp; c");
+        assert_eq!(polybar_escape("100%{F#f00}"), "100%%{F#f00}");
+        assert!(matches!(polybar_escape("★ Alice"), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn ago() {
+        assert_eq!(format_ago(TimeDelta::seconds(-5)), "just now");
+        assert_eq!(format_ago(TimeDelta::seconds(59)), "just now");
+        assert_eq!(format_ago(TimeDelta::minutes(1)), "1 minute ago");
+        assert_eq!(format_ago(TimeDelta::minutes(59)), "59 minutes ago");
+        assert_eq!(format_ago(TimeDelta::minutes(60)), "1 hour ago");
+        assert_eq!(format_ago(TimeDelta::minutes(150)), "2 hours ago");
+        assert_eq!(format_ago(TimeDelta::hours(24)), "1 day ago");
+        assert_eq!(format_ago(TimeDelta::days(3)), "3 days ago");
+    }
+}
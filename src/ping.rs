@@ -0,0 +1,267 @@
+//! A client for the [Server List Ping](https://minecraft.wiki/w/Java_Edition_protocol/Server_List_Ping) protocol of Minecraft: Java Edition.
+
+use {
+    std::{
+        collections::HashSet,
+        time::Duration,
+    },
+    serde::Deserialize,
+    serde_json::Value as Json,
+    tokio::{
+        io::{
+            AsyncRead,
+            AsyncReadExt as _,
+            AsyncWriteExt as _,
+        },
+        net::TcpStream,
+        time::timeout,
+    },
+    crate::{
+        Error,
+        People,
+        Status,
+        Uid,
+    },
+};
+
+pub const DEFAULT_PORT: u16 = 25565;
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// The maximum length of a packet, as defined by the protocol.
+const MAX_PACKET_LEN: usize = 2097151;
+
+#[derive(Debug, Deserialize)]
+pub struct Response {
+    pub version: Version,
+    pub players: Players,
+    #[serde(default)]
+    pub description: Json,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Version {
+    pub name: String,
+    pub protocol: i32,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Players {
+    pub max: u32,
+    pub online: u32,
+    #[serde(default)]
+    pub sample: Vec<SamplePlayer>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SamplePlayer {
+    pub name: String,
+    pub id: String,
+}
+
+fn write_var_int(buf: &mut Vec<u8>, value: i32) {
+    let mut value = value as u32;
+    loop {
+        if value & !0x7f == 0 {
+            buf.push(value as u8);
+            break
+        }
+        buf.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+}
+
+async fn read_var_int(reader: &mut (impl AsyncRead + Unpin)) -> Result<i32, Error> {
+    let mut value = 0u32;
+    for i in 0..5 {
+        let byte = reader.read_u8().await?;
+        value |= u32::from(byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 { return Ok(value as i32) }
+    }
+    Err(Error::VarIntTooLong)
+}
+
+fn write_string(buf: &mut Vec<u8>, s: &str) {
+    write_var_int(buf, s.len() as i32);
+    buf.extend_from_slice(s.as_bytes());
+}
+
+/// Splits an address like `example.com:25565` or `[::1]:25565` into host and port, defaulting to [`DEFAULT_PORT`].
+///
+/// An IPv6 address without brackets, like `::1`, is always used with the default port.
+pub fn parse_address(address: &str) -> Result<(&str, u16), Error> {
+    let invalid = || Error::InvalidAddress(address.to_owned());
+    let (host, port) = if let Some(rest) = address.strip_prefix('[') {
+        let (host, rest) = rest.split_once(']').ok_or_else(invalid)?;
+        (host, if rest.is_empty() { None } else { Some(rest.strip_prefix(':').ok_or_else(invalid)?) })
+    } else if address.matches(':').count() > 1 {
+        (address, None)
+    } else if let Some((host, port)) = address.split_once(':') {
+        (host, Some(port))
+    } else {
+        (address, None)
+    };
+    if host.is_empty() { return Err(invalid()) }
+    Ok((host, port.map(str::parse).transpose().map_err(|_| invalid())?.unwrap_or(DEFAULT_PORT)))
+}
+
+/// Performs a handshake with the server at the given address and requests its status.
+pub async fn ping(host: &str, port: u16) -> Result<Response, Error> {
+    timeout(TIMEOUT, async {
+        let mut stream = TcpStream::connect((host, port)).await?;
+        let mut handshake = Vec::default();
+        write_var_int(&mut handshake, 0x00); // packet ID
+        write_var_int(&mut handshake, -1); // protocol version, -1 means unknown
+        write_string(&mut handshake, host);
+        handshake.extend_from_slice(&port.to_be_bytes());
+        write_var_int(&mut handshake, 1); // next state: status
+        let mut packet = Vec::default();
+        write_var_int(&mut packet, handshake.len() as i32);
+        packet.extend(handshake);
+        packet.extend_from_slice(&[0x01, 0x00]); // status request: length 1, packet ID 0
+        stream.write_all(&packet).await?;
+        let len = usize::try_from(read_var_int(&mut stream).await?).map_err(|_| Error::PacketTooLong)?;
+        if len > MAX_PACKET_LEN { return Err(Error::PacketTooLong) }
+        let mut body = vec![0; len];
+        stream.read_exact(&mut body).await?;
+        let mut body = &body[..];
+        let packet_id = read_var_int(&mut body).await?;
+        if packet_id != 0x00 { return Err(Error::UnexpectedPacketId(packet_id)) }
+        let json_len = usize::try_from(read_var_int(&mut body).await?).map_err(|_| Error::PacketTooLong)?;
+        Ok(serde_json::from_slice(body.get(..json_len).ok_or(Error::PacketTooLong)?)?)
+    }).await.map_err(|_| Error::PingTimeout)?
+}
+
+/// Removes hyphens from a UUID and converts it to lowercase so UUIDs in different notations can be compared.
+fn normalize_uuid(uuid: &str) -> String {
+    uuid.chars().filter(|&c| c != '-').flat_map(char::to_lowercase).collect()
+}
+
+impl Response {
+    /// The message of the day as plain text, with formatting removed.
+    pub fn motd(&self) -> String {
+        fn collect_text(component: &Json, text: &mut String) {
+            match component {
+                Json::String(s) => text.push_str(s),
+                Json::Array(components) => for component in components { collect_text(component, text) },
+                Json::Object(obj) => {
+                    if let Some(Json::String(s)) = obj.get("text") { text.push_str(s) }
+                    if let Some(extra) = obj.get("extra") { collect_text(extra, text) }
+                }
+                _ => {}
+            }
+        }
+
+        let mut text = String::default();
+        collect_text(&self.description, &mut text);
+        // remove legacy formatting codes
+        let mut chars = text.chars();
+        let mut motd = String::with_capacity(text.len());
+        while let Some(c) = chars.next() {
+            if c == '§' { chars.next(); } else { motd.push(c) }
+        }
+        motd
+    }
+
+    /// Converts the response into a world status.
+    ///
+    /// Players in the sample are identified using the Minecraft UUIDs in `people`, falling back to [`Uid::Minecraft`].
+    /// Servers only include up to 12 players in the sample, and players who hide themselves appear with the nil UUID, so players not in the sample are counted as [`Status::unlisted`].
+    pub fn into_status(self, people: &People) -> Status {
+        let motd = self.motd();
+        let mut seen = HashSet::new();
+        let list = self.players.sample.into_iter()
+            .filter(|player| {
+                let uuid = normalize_uuid(&player.id);
+                uuid.bytes().any(|b| b != b'0') && seen.insert(uuid)
+            })
+            .map(|player| {
+                let uuid = normalize_uuid(&player.id);
+                people.people.iter()
+                    .find(|(_, person)| person.minecraft.as_ref().and_then(|minecraft| minecraft.uuid.as_deref()).is_some_and(|person_uuid| normalize_uuid(person_uuid) == uuid))
+                    .map_or_else(|| Uid::Minecraft { minecraft: player.name }, |(uid, _)| uid.clone())
+            })
+            .collect::<Vec<_>>();
+        Status {
+            unlisted: usize::try_from(self.players.online).unwrap_or(usize::MAX).saturating_sub(list.len()),
+            list,
+            running: true,
+            version: Some(self.version.name),
+            motd: Some(motd),
+            modloader: None,
+            modpack: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        tokio::net::TcpListener,
+        crate::{
+            MinecraftData,
+            Person,
+        },
+        super::*,
+    };
+
+    #[test]
+    fn addresses() {
+        assert_eq!(parse_address("wurstmineberg.de").unwrap(), ("wurstmineberg.de", DEFAULT_PORT));
+        assert_eq!(parse_address("localhost:25566").unwrap(), ("localhost", 25566));
+        assert_eq!(parse_address("[::1]:25566").unwrap(), ("::1", 25566));
+        assert_eq!(parse_address("[::1]").unwrap(), ("::1", DEFAULT_PORT));
+        assert_eq!(parse_address("2001:db8::1").unwrap(), ("2001:db8::1", DEFAULT_PORT));
+        assert!(parse_address("localhost:port").is_err());
+        assert!(parse_address("[::1]25566").is_err());
+        assert!(parse_address(":25565").is_err());
+    }
+
+    #[tokio::test]
+    async fn ping_local_server() {
+        const UUID: &str = "fda5a5b5-8bd2-4bc5-bd5f-a3ca1c0bd6ff";
+        const RESPONSE: &str = r#"{
+            "version": {"name": "1.21.4", "protocol": 769},
+            "players": {"max": 20, "online": 15, "sample": [
+                {"name": "fenhl", "id": "fda5a5b5-8bd2-4bc5-bd5f-a3ca1c0bd6ff"},
+                {"name": "Anonymous Player", "id": "00000000-0000-0000-0000-000000000000"},
+                {"name": "Anonymous Player", "id": "00000000-0000-0000-0000-000000000000"},
+                {"name": "Notch", "id": "069a79f4-44e9-4726-a5be-fca90e38aaf5"},
+                {"name": "Notch", "id": "069a79f444e94726a5befca90e38aaf5"}
+            ]},
+            "description": {"text": "§aWurst§rmineberg", "extra": [" server"]}
+        }"#;
+
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            // handshake
+            let len = read_var_int(&mut stream).await.unwrap();
+            let mut handshake = vec![0; len as usize];
+            stream.read_exact(&mut handshake).await.unwrap();
+            // status request
+            let mut request = [0; 2];
+            stream.read_exact(&mut request).await.unwrap();
+            assert_eq!(request, [0x01, 0x00]);
+            let mut body = Vec::default();
+            write_var_int(&mut body, 0x00);
+            write_string(&mut body, RESPONSE);
+            let mut packet = Vec::default();
+            write_var_int(&mut packet, body.len() as i32);
+            packet.extend(body);
+            stream.write_all(&packet).await.unwrap();
+        });
+        let response = ping("127.0.0.1", port).await.unwrap();
+        server.await.unwrap();
+        assert_eq!(response.motd(), "Wurstmineberg server");
+        let people = People {
+            people: [(Uid::WmbId(format!("fenhl")), Person {
+                minecraft: Some(MinecraftData { nicks: Vec::default(), uuid: Some(UUID.to_owned()) }),
+                ..Person::default()
+            })].into_iter().collect(),
+        };
+        let status = response.into_status(&people);
+        assert_eq!(status.version.as_deref(), Some("1.21.4"));
+        assert_eq!(status.list, [Uid::WmbId(format!("fenhl")), Uid::Minecraft { minecraft: format!("Notch") }]);
+        assert_eq!(status.unlisted, 13);
+    }
+}
@@ -0,0 +1,427 @@
+use {
+    std::{
+        borrow::Cow,
+        collections::{
+            BTreeMap,
+            HashSet,
+        },
+        future::Future,
+    },
+    chrono::{
+        TimeDelta,
+        prelude::*,
+    },
+    itertools::Itertools as _,
+    serde::{
+        Serialize,
+        de::DeserializeOwned,
+    },
+    bitbar_wurstmineberg_status::{
+        Client,
+        Color,
+        DiscordData,
+        ModLoaderInfo,
+        ModpackInfo,
+        People,
+        Status,
+        Uid,
+        ping,
+    },
+    crate::{
+        Error,
+        files::{
+            Config,
+            Data,
+            Snapshot,
+            WorldDeferrals,
+        },
+        history,
+        launcher::{
+            self,
+            SyncReport,
+        },
+        notify::{
+            self,
+            EventKind,
+        },
+    },
+};
+
+/// Why the plugin is hidden.
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) enum Hidden {
+    /// The plugin has been deferred using a `deferSpecs` menu item.
+    Deferred,
+    /// The main world is running but no players are online, and `showIfEmpty` is disabled.
+    Empty,
+    /// The main world is not running and no players are online, and `showIfOffline` is disabled.
+    Offline,
+    /// None of the `watchedPlayers` are online, and `showIfUnwatched` is disabled.
+    Unwatched,
+}
+
+/// Everything the output backends need to know, computed from the API responses and the config.
+#[derive(Debug, Default)]
+pub(crate) struct State {
+    /// If the plugin is deferred, the time until which it is deferred.
+    pub(crate) deferred: Option<DateTime<Utc>>,
+    pub(crate) hidden: Option<Hidden>,
+    /// Worlds muted using `defer_world`, by server name and world name, with the time until which they're muted. These are omitted from `servers`, except for main worlds, which are kept without players.
+    pub(crate) muted: WorldDeferrals,
+    /// Errors which occurred while loading the API responses, along with the time the snapshot used instead was retrieved.
+    pub(crate) stale: Vec<(DateTime<Utc>, Error)>,
+    pub(crate) servers: Vec<Server>,
+    /// Players who left a world within the last `recentlyOnlineHours` hours and aren't online anymore, most recent first.
+    pub(crate) recently_online: Vec<RecentPlayer>,
+    /// The last time each of the `watchedPlayers` who isn't online was seen, most recent first. Players who don't appear in the session history are omitted.
+    pub(crate) watched_last_seen: Vec<RecentPlayer>,
+    /// The outcome of syncing launcher profiles according to `versionMatch`.
+    pub(crate) launcher_sync: SyncReport,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Server {
+    pub(crate) name: String,
+    pub(crate) main_world: String,
+    /// The API client for this server, or `None` if its status is only retrieved using Server List Ping.
+    #[serde(skip)]
+    pub(crate) client: Option<Client>,
+    #[serde(skip)]
+    pub(crate) people: People,
+    pub(crate) worlds: BTreeMap<String, World>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct World {
+    pub(crate) running: bool,
+    pub(crate) version: Option<String>,
+    pub(crate) motd: Option<String>,
+    pub(crate) modloader: Option<ModLoaderInfo>,
+    pub(crate) modpack: Option<ModpackInfo>,
+    pub(crate) players: Vec<Player>,
+    /// The number of players online who aren't included in `players`, see [`Status::unlisted`].
+    pub(crate) unlisted: usize,
+}
+
+impl World {
+    /// The number of players online, including unlisted ones.
+    pub(crate) fn total(&self) -> usize {
+        self.players.len() + self.unlisted
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Player {
+    pub(crate) uid: Uid,
+    pub(crate) name: String,
+    pub(crate) discord: Option<DiscordData>,
+    pub(crate) fav_color: Option<Color>,
+    /// Whether this player is listed in `watchedPlayers`.
+    pub(crate) watched: bool,
+}
+
+impl Player {
+    fn new(uid: Uid, people: &People, config: &Config) -> Self {
+        let person = people.get(&uid).cloned().unwrap_or_default();
+        Self {
+            name: person.name.unwrap_or_else(|| uid.to_string()),
+            discord: person.discord,
+            fav_color: person.fav_color,
+            watched: config.watched_players.contains(&uid),
+            uid,
+        }
+    }
+
+    /// The name to display, with a star for watched players.
+    pub(crate) fn label(&self) -> Cow<'_, str> {
+        if self.watched {
+            Cow::Owned(format!("★ {}", self.name))
+        } else {
+            Cow::Borrowed(&self.name)
+        }
+    }
+}
+
+/// A player who left a world, from the session history. See [`State::recently_online`] and [`State::watched_last_seen`].
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct RecentPlayer {
+    pub(crate) server: String,
+    pub(crate) world: String,
+    pub(crate) left: DateTime<Utc>,
+    #[serde(flatten)]
+    pub(crate) player: Player,
+}
+
+/// Falls back to the last successful response from the given endpoint if loading fails, noting the error in `stale`.
+async fn load_or_snapshot<T: Serialize + DeserializeOwned, E: Into<Error>>(server: &str, endpoint: &str, save: bool, stale: &mut Vec<(DateTime<Utc>, Error)>, load: impl Future<Output = Result<T, E>>) -> Result<T, Error> {
+    match load.await.map_err(E::into) {
+        Ok(value) => {
+            if save { Snapshot::save(server, endpoint, &value)? }
+            Ok(value)
+        }
+        Err(e) => match Snapshot::load(server, endpoint) {
+            Ok(Some(snapshot)) => {
+                stale.push((snapshot.retrieved, e));
+                Ok(snapshot.value)
+            }
+            Ok(None) => Err(e),
+            // the snapshot is only a fallback, so the error from the API is the one to report
+            Err(snapshot_error) => Err(Error::SnapshotFallback(Box::new(e), Box::new(snapshot_error))),
+        },
+    }
+}
+
+impl Server {
+    pub(crate) fn total(&self) -> usize {
+        self.worlds.values().map(World::total).sum()
+    }
+
+    /// Whether the main world is running. A main world which is missing from the world list counts as not running.
+    pub(crate) fn main_world_running(&self) -> bool {
+        self.worlds.get(&self.main_world).is_some_and(|world| world.running)
+    }
+
+    /// Worlds which should be listed, i.e. the main world if it's offline and any world with players online.
+    pub(crate) fn listed_worlds(&self) -> impl Iterator<Item = (&String, &World)> {
+        self.worlds.iter().filter(|(world_name, world)| (**world_name == self.main_world && !world.running) || world.total() > 0)
+    }
+
+    /// Why this server alone would cause the plugin to be hidden, if at all.
+    fn hidden(&self, config: &Config) -> Option<Hidden> {
+        if self.total() > 0 {
+            None
+        } else if self.main_world_running() {
+            (!config.show_if_empty).then_some(Hidden::Empty)
+        } else {
+            (!config.show_if_offline).then_some(Hidden::Offline)
+        }
+    }
+}
+
+/// Which side effects loading the [`State`] has.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    /// Snapshots, plugin data, and session history are written, notifications are sent, and launcher profiles are synced.
+    Default,
+    /// Nothing is modified: no snapshots, plugin data, or session history are written,
+    /// no notifications are sent, deferrals aren't cancelled, and launcher profiles are only checked.
+    ReadOnly,
+}
+
+impl State {
+    /// Loads the current state from the API and updates the plugin data, sending notifications and syncing launcher profiles as configured.
+    ///
+    /// The API is queried even if the plugin is deferred, so the session history stays complete,
+    /// but no notifications are sent while deferred unless `showDeferred` or `undeferPlayers` is configured.
+    pub(crate) async fn load(http_client: &reqwest::Client, config: &Config) -> Result<Self, Error> {
+        Self::load_inner(http_client, config, Mode::Default).await
+    }
+
+    /// Queries the API like [`State::load`], but without any side effects.
+    ///
+    /// No snapshots, plugin data, or session history are written, no notifications are sent, a deferral is reported as cancelled by `undeferPlayers` but not actually cancelled, and launcher profiles are only checked.
+    pub(crate) async fn load_read_only(http_client: &reqwest::Client, config: &Config) -> Result<Self, Error> {
+        Self::load_inner(http_client, config, Mode::ReadOnly).await
+    }
+
+    async fn load_inner(http_client: &reqwest::Client, config: &Config, mode: Mode) -> Result<Self, Error> {
+        let data = Data::load()?;
+        let Some(deferred) = data.active_deferral() else { return Self::load_with_data(http_client, config, data, mode, true).await };
+        // a deferral which hides the plugin entirely also silences notifications
+        let send_notifications = config.show_deferred || !config.undefer_players.is_empty();
+        let mut state = Self::load_with_data(http_client, config, data, mode, send_notifications).await?;
+        if state.players().any(|player| config.undefer_players.contains(&player.uid)) {
+            if mode != Mode::ReadOnly {
+                let mut data = Data::load()?;
+                data.deferred = None;
+                data.save()?;
+            }
+        } else {
+            state.deferred = Some(deferred);
+            // with showDeferred, the plugin stays visible if it would be visible without the deferral
+            if !config.show_deferred || state.hidden.is_some() {
+                state.hidden = Some(Hidden::Deferred);
+            }
+        }
+        Ok(state)
+    }
+
+    async fn load_with_data(http_client: &reqwest::Client, config: &Config, mut data: Data, mode: Mode, send_notifications: bool) -> Result<Self, Error> {
+        let mut stale = Vec::default();
+        let mut servers = Vec::default();
+        let muted = data.active_world_deferrals();
+        for server_config in config.servers().iter() {
+            let server_muted = muted.get(&server_config.name);
+            let is_muted = |world_name: &String| server_muted.is_some_and(|worlds| worlds.contains_key(world_name));
+            let client = server_config.api_base_url.clone().map(|api_base_url| {
+                let client = Client::new(http_client.clone(), api_base_url);
+                if let Some(site_url) = server_config.site_url.clone() { client.with_site_url(site_url) } else { client }
+            });
+            let mut people_missing = false;
+            let people = if let Some(ref client) = client {
+                match load_or_snapshot(&server_config.name, "people", mode != Mode::ReadOnly, &mut stale, client.people()).await {
+                    Ok(people) => people,
+                    // the world statuses are queried using Server List Ping, so they can still be shown, just without names and avatars
+                    Err(e) if !server_config.ping_addresses.is_empty() => {
+                        stale.push((Utc::now(), e));
+                        people_missing = true;
+                        People::default()
+                    }
+                    Err(e) => return Err(e),
+                }
+            } else {
+                People::default()
+            };
+            let people_stale = stale.len();
+            let mut statuses = if server_config.ping_addresses.is_empty() {
+                let client = client.as_ref().ok_or_else(|| Error::NoStatusSource(server_config.name.clone()))?;
+                load_or_snapshot(&server_config.name, "worlds", mode != Mode::ReadOnly, &mut stale, client.worlds()).await?
+            } else {
+                let mut statuses = BTreeMap::default();
+                for (world_name, address) in &server_config.ping_addresses {
+                    let (host, port) = ping::parse_address(address)?;
+                    statuses.insert(world_name.clone(), match ping::ping(host, port).await {
+                        Ok(response) => response.into_status(&people),
+                        // the server is unreachable, which most likely means it's not running
+                        Err(_) => Status { list: Vec::default(), unlisted: 0, running: false, version: None, motd: None, modloader: None, modpack: None },
+                    });
+                }
+                statuses
+            };
+            for status in statuses.values_mut() {
+                status.list.retain(|uid| !config.ignored_players.contains(uid) && !server_config.ignored_players.contains(uid));
+            }
+            // player lists from a snapshot may be older than the ones from the last run, so only compare fresh ones
+            // without the people list, pinged players are identified by Minecraft username instead, so comparing would report everyone as having left and rejoined
+            if stale.len() == people_stale && !people_missing && mode != Mode::ReadOnly {
+                let previous = data.online.entry(server_config.name.clone()).or_default();
+                let events = notify::diff(&server_config.name, previous, &statuses);
+                let online = statuses.iter().map(|(world_name, status)| (world_name.clone(), status.list.clone())).collect::<BTreeMap<_, _>>();
+                if *previous != online {
+                    *previous = online;
+                    // saved before recording and notifying, so an error later on doesn't cause the same events to be recorded or sent again on the next run
+                    data.save()?;
+                }
+                history::record(Utc::now(), &events)?;
+                if send_notifications && let Some(ref notify_config) = config.notify {
+                    for event in events.iter().filter(|event| !is_muted(&event.world) && notify_config.matches(event)) {
+                        // a notifier failing shouldn't break the menu
+                        if let Err(e) = notify_config.send(event, &people) {
+                            eprintln!("warning: failed to send notification: {e}");
+                        }
+                    }
+                }
+            }
+            // muting is applied after updating the player lists so unmuting a world doesn't cause notifications for players who were already online
+            statuses.retain(|world_name, status| if !is_muted(world_name) {
+                true
+            } else if *world_name == server_config.main_world {
+                // keep the main world so it still determines whether the server is offline
+                status.list.clear();
+                status.unlisted = 0;
+                true
+            } else {
+                false
+            });
+            servers.push(Server {
+                name: server_config.name.clone(),
+                main_world: server_config.main_world.clone(),
+                worlds: statuses.into_iter().map(|(world_name, status)| (world_name, World {
+                    running: status.running,
+                    version: status.version,
+                    motd: status.motd,
+                    modloader: status.modloader,
+                    modpack: status.modpack,
+                    players: {
+                        let mut players = status.list.into_iter().map(|uid| Player::new(uid, &people, config)).collect_vec();
+                        // stable sort, so the order from the API is kept otherwise
+                        players.sort_by_key(|player| !player.watched);
+                        players
+                    },
+                    unlisted: status.unlisted,
+                })).collect(),
+                client, people,
+            });
+        }
+        let recently_since = (config.recently_online_hours > 0).then(|| Utc::now() - TimeDelta::hours(config.recently_online_hours.into()));
+        // pruned even if neither submenu is shown, so the history file doesn't keep growing
+        let history = history::prune(Utc::now() - history::retention().max(TimeDelta::hours(config.recently_online_hours.into())), mode != Mode::ReadOnly)?;
+        let mut recently_online = Vec::default();
+        let mut watched_last_seen = Vec::default();
+        if recently_since.is_some() || !config.watched_players.is_empty() {
+            let online = servers.iter().flat_map(|server| server.worlds.values()).flat_map(|world| &world.players).map(|player| &player.uid).collect::<HashSet<_>>();
+            let last_left = history.into_iter()
+                .rev()
+                .filter(|entry| entry.event.kind == EventKind::Leave && !online.contains(&entry.event.uid) && !config.ignored_players.contains(&entry.event.uid))
+                .unique_by(|entry| entry.event.uid.clone());
+            for entry in last_left {
+                // players from servers which have since been removed from the config are omitted
+                let Some(server) = servers.iter().find(|server| server.name == entry.event.server) else { continue };
+                let recent = RecentPlayer {
+                    player: Player::new(entry.event.uid, &server.people, config),
+                    server: entry.event.server,
+                    world: entry.event.world,
+                    left: entry.time,
+                };
+                if recently_since.is_some_and(|since| recent.left >= since) && !muted.get(&recent.server).is_some_and(|worlds| worlds.contains_key(&recent.world)) {
+                    if recent.player.watched { watched_last_seen.push(recent.clone()) }
+                    recently_online.push(recent);
+                } else if recent.player.watched {
+                    watched_last_seen.push(recent);
+                }
+            }
+        }
+        let launcher_sync = launcher::sync(config, &servers, mode == Mode::ReadOnly);
+        // the plugin is only hidden if every server would hide it
+        let mut hidden = servers.iter().map(|server| server.hidden(config)).collect::<Option<Vec<_>>>().and_then(|reasons| reasons.into_iter().next());
+        if hidden.is_none() && !config.show_if_unwatched && !config.watched_players.is_empty() && !servers.iter().flat_map(|server| server.worlds.values()).flat_map(|world| &world.players).any(|player| player.watched) {
+            hidden = Some(Hidden::Unwatched);
+        }
+        Ok(Self { deferred: None, hidden, muted, stale, servers, recently_online, watched_last_seen, launcher_sync })
+    }
+
+    pub(crate) fn total(&self) -> usize {
+        self.servers.iter().map(Server::total).sum()
+    }
+
+    /// Whether the main world of each server is running.
+    pub(crate) fn main_world_running(&self) -> bool {
+        self.servers.iter().all(Server::main_world_running)
+    }
+
+    /// The favorite color of the only player online, if `singleColor` is enabled.
+    pub(crate) fn single_color(&self, config: &Config) -> Option<Color> {
+        if !config.single_color || self.total() != 1 { return None }
+        self.players().exactly_one().ok()?.fav_color
+    }
+
+    /// The number of `watchedPlayers` online.
+    pub(crate) fn watched_total(&self) -> usize {
+        self.players().filter(|player| player.watched).count()
+    }
+
+    /// All players online on any world of any server.
+    pub(crate) fn players(&self) -> impl Iterator<Item = &Player> {
+        self.servers.iter().flat_map(|server| server.worlds.values()).flat_map(|world| &world.players)
+    }
+
+    /// The number of players online on any world of any server who aren't included in [`State::players`], see [`World::unlisted`].
+    pub(crate) fn unlisted(&self) -> usize {
+        self.servers.iter().flat_map(|server| server.worlds.values()).map(|world| world.unlisted).sum()
+    }
+
+    /// Whether the plugin should be hidden from status bars other than BitBar, which shows the deferral instead if `showDeferred` is enabled.
+    pub(crate) fn is_hidden(&self) -> bool {
+        self.hidden.is_some() || self.deferred.is_some()
+    }
+
+    /// The time at which the oldest snapshot used instead of a failed API response was retrieved.
+    pub(crate) fn stale_since(&self) -> Option<DateTime<Utc>> {
+        self.stale.iter().map(|&(retrieved, _)| retrieved).min()
+    }
+}
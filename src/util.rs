@@ -1,7 +1,26 @@
 use {
     std::{
         convert::Infallible,
-        io::Cursor,
+        ffi::OsString,
+        fs::{
+            self,
+            File,
+        },
+        hash::{
+            DefaultHasher,
+            Hasher as _,
+        },
+        io::{
+            self,
+            Cursor,
+            prelude::*,
+        },
+        path::{
+            Path,
+            PathBuf,
+        },
+        process,
+        time::SystemTime,
     },
     async_trait::async_trait,
     image::{
@@ -10,6 +29,14 @@ use {
     },
     mime::Mime,
     reqwest::header::CONTENT_TYPE,
+    serde::{
+        Deserialize,
+        Deserializer,
+        de::{
+            self,
+            Visitor,
+        },
+    },
     crate::Error,
 };
 
@@ -59,3 +86,216 @@ impl<T> ResultNeverExt for Result<T, Infallible> {
         }
     }
 }
+
+/// The keys of a config struct, as expected by its `Deserialize` implementation.
+pub(crate) fn struct_fields<'de, T: Deserialize<'de>>() -> &'static [&'static str] {
+    /// A deserializer which records the field names requested by a struct's `Deserialize` implementation, then fails.
+    struct FieldsDeserializer<'a>(&'a mut &'static [&'static str]);
+
+    impl<'de> Deserializer<'de> for FieldsDeserializer<'_> {
+        type Error = de::value::Error;
+
+        fn deserialize_any<V: Visitor<'de>>(self, _: V) -> Result<V::Value, Self::Error> {
+            Err(de::Error::custom("expected a struct"))
+        }
+
+        fn deserialize_struct<V: Visitor<'de>>(self, _: &'static str, fields: &'static [&'static str], _: V) -> Result<V::Value, Self::Error> {
+            *self.0 = fields;
+            Err(de::Error::custom("recorded struct fields"))
+        }
+
+        serde::forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+            bytes byte_buf option unit unit_struct newtype_struct seq tuple
+            tuple_struct map enum identifier ignored_any
+        }
+    }
+
+    let mut fields: &'static [&'static str] = &[];
+    let _ = T::deserialize(FieldsDeserializer(&mut fields));
+    fields
+}
+
+/// Encodes a name, e.g. of a server or player, as a single path component that can't refer to another directory.
+///
+/// ASCII alphanumerics, `-`, and `_` are kept as is. Other bytes are percent-encoded, so distinct names are never encoded the same way.
+/// The empty name is encoded as `%`, which is not the encoding of any other name.
+pub(crate) fn path_component(name: &str) -> String {
+    if name.is_empty() { return format!("%") }
+    let mut encoded = String::with_capacity(name.len());
+    for byte in name.bytes() {
+        if byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_' {
+            encoded.push(char::from(byte));
+        } else {
+            encoded.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    encoded
+}
+
+/// The number of backups kept of files owned by other programs, such as the Minecraft launcher.
+const BACKUP_COUNT: u8 = 3;
+
+/// Follows symbolic links until reaching a path which isn't one, which may not exist yet.
+fn resolve_symlinks(path: &Path) -> io::Result<PathBuf> {
+    let mut path = path.to_owned();
+    // the same limit as Linux, to fail on symlink loops
+    for _ in 0..40 {
+        match fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.file_type().is_symlink() => {
+                let target = fs::read_link(&path)?;
+                // relative targets are relative to the link's folder, absolute targets replace the path entirely
+                path = path.parent().map_or_else(|| target.clone(), |parent| parent.join(&target));
+            }
+            Ok(_) => return Ok(path),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(path),
+            Err(e) => return Err(e),
+        }
+    }
+    Err(io::Error::other(format!("too many levels of symbolic links at {}", path.display())))
+}
+
+/// Writes to a temporary file in the same directory, then renames it to the given path, so the file is never left partially written.
+///
+/// If the path is a symbolic link, the file it points to is replaced instead of the link. The permissions of an existing file are kept.
+pub(crate) fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let path = resolve_symlinks(path)?;
+    let permissions = match fs::metadata(&path) {
+        Ok(metadata) => Some(metadata.permissions()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e),
+    };
+    let mut tmp_name = OsString::from(".");
+    tmp_name.push(path.file_name().unwrap_or_default());
+    tmp_name.push(format!(".{}.tmp", process::id()));
+    let tmp_path = path.with_file_name(tmp_name);
+    let result = File::create(&tmp_path)
+        .and_then(|mut file| {
+            file.write_all(contents)?;
+            if let Some(permissions) = permissions { file.set_permissions(permissions)? }
+            file.sync_all()
+        })
+        .and_then(|()| fs::rename(&tmp_path, &path));
+    if result.is_err() {
+        // don't leave the temporary file behind, the original error is more relevant than any error from removing it
+        let _ = fs::remove_file(&tmp_path);
+    }
+    result
+}
+
+fn backup_path(path: &Path, n: u8) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_owned();
+    name.push(format!(".bak.{n}"));
+    path.with_file_name(name)
+}
+
+/// Copies the file to `<name>.bak.1`, moving existing backups to `<name>.bak.2` and so on, up to [`BACKUP_COUNT`].
+fn rotate_backups(path: &Path) -> io::Result<()> {
+    for n in (1..BACKUP_COUNT).rev() {
+        match fs::rename(backup_path(path, n), backup_path(path, n + 1)) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+    }
+    fs::copy(path, backup_path(path, 1))?;
+    Ok(())
+}
+
+/// The state of a file owned by another program as of when it was read, to avoid overwriting changes that program made in the meantime.
+pub(crate) struct FileVersion {
+    modified: SystemTime,
+    hash: u64,
+}
+
+impl FileVersion {
+    fn hash(contents: &[u8]) -> u64 {
+        let mut hasher = DefaultHasher::default();
+        hasher.write(contents);
+        hasher.finish()
+    }
+
+    /// Reads the file along with its current version.
+    pub(crate) fn read(path: &Path) -> io::Result<(Vec<u8>, Self)> {
+        // read the metadata first so a concurrent write shows up as a changed modification time
+        let modified = fs::metadata(path)?.modified()?;
+        let contents = fs::read(path)?;
+        let hash = Self::hash(&contents);
+        Ok((contents, Self { modified, hash }))
+    }
+
+    /// Overwrites the file, keeping backups of the previous contents.
+    ///
+    /// Fails if the file was modified since it was read, unless its contents are unchanged.
+    pub(crate) fn write(&mut self, path: &Path, contents: &[u8]) -> Result<(), Error> {
+        if fs::metadata(path)?.modified()? != self.modified && Self::hash(&fs::read(path)?) != self.hash {
+            return Err(Error::FileChanged(path.to_owned()))
+        }
+        rotate_backups(path)?;
+        write_atomic(path, contents)?;
+        self.modified = fs::metadata(path)?.modified()?;
+        self.hash = Self::hash(contents);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn path_components() {
+        assert_eq!(path_component("Wurstmineberg"), "Wurstmineberg");
+        assert_eq!(path_component("88954390683947008"), "88954390683947008");
+        assert_eq!(path_component("../people"), "%2E%2E%2Fpeople");
+        assert_eq!(path_component("C:\\x y"), "C%3A%5Cx%20y");
+        assert_eq!(path_component("Würst"), "W%C3%BCrst");
+        assert_eq!(path_component(""), "%");
+        assert_ne!(path_component("%41"), path_component("A"));
+    }
+
+    #[test]
+    fn write_atomic_replaces_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.json");
+        write_atomic(&path, b"1").unwrap();
+        write_atomic(&path, b"2").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"2");
+        // no temporary files are left behind
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn write_atomic_follows_symlinks() {
+        use std::os::unix::fs::symlink;
+
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("synced")).unwrap();
+        let target = dir.path().join("synced").join("launcher_profiles.json");
+        fs::write(&target, b"old").unwrap();
+        let link = dir.path().join("launcher_profiles.json");
+        symlink(Path::new("synced").join("launcher_profiles.json"), &link).unwrap();
+        write_atomic(&link, b"new").unwrap();
+        assert!(fs::symlink_metadata(&link).unwrap().file_type().is_symlink());
+        assert_eq!(fs::read(&target).unwrap(), b"new");
+        // a dangling link is written through as well
+        let dangling = dir.path().join("dangling.json");
+        symlink("missing.json", &dangling).unwrap();
+        write_atomic(&dangling, b"created").unwrap();
+        assert_eq!(fs::read(dir.path().join("missing.json")).unwrap(), b"created");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn write_atomic_keeps_permissions() {
+        use std::os::unix::fs::PermissionsExt as _;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("launcher_profiles.json");
+        fs::write(&path, b"old").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+        write_atomic(&path, b"new").unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+    }
+}